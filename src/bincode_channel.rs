@@ -1,28 +1,30 @@
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
-use tokio::net::TcpStream;
-use tokio::io::{ReadHalf, WriteHalf};
-use bincode_codec::{BincodeReader, BincodeWriter};
-use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use bincode_codec::{BincodeReader, BincodeWriter, Compression};
+use keyring::Keyring;
+use types::Message;
 
 // TODO Refactor into bincode_codec and delete
 
 /// Utility
 
-pub type FramedReader = FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>;
-pub type FramedWriter = FramedWrite<WriteHalf<TcpStream>, LengthDelimitedCodec>;
+pub type FramedReader<S> = FramedRead<ReadHalf<S>, LengthDelimitedCodec>;
+pub type FramedWriter<S> = FramedWrite<WriteHalf<S>, LengthDelimitedCodec>;
 
-pub fn new_reader<T>(read_half: ReadHalf<TcpStream>) -> BincodeReader<FramedReader, T>
+pub fn new_reader<S, T>(read_half: ReadHalf<S>, keyring: Keyring, compression: Compression) -> BincodeReader<FramedReader<S>, T>
     where
-      for<'a> T: Deserialize<'a>
+      S: AsyncRead,
+      T: Message
 {
     let length_delimited = FramedRead::new(read_half, LengthDelimitedCodec::new());
-    BincodeReader::<_, T>::new(length_delimited)
+    BincodeReader::<_, T>::new(length_delimited, keyring, compression)
 }
 
-pub fn new_writer<T>(write_half: WriteHalf<TcpStream>) -> BincodeWriter<FramedWriter, T>
+pub fn new_writer<S, T>(write_half: WriteHalf<S>, keyring: Keyring, compression: Compression) -> BincodeWriter<FramedWriter<S>, T>
     where
-      T: Serialize
+      S: AsyncWrite,
+      T: Message
 {
     let length_delimited = FramedWrite::new(write_half, LengthDelimitedCodec::new());
-    BincodeWriter::new(length_delimited)
+    BincodeWriter::new(length_delimited, keyring, compression)
 }
@@ -1,19 +1,138 @@
 use std::marker::PhantomData;
-use std::io;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use bincode;
 use bytes::{Bytes, BytesMut};
-use futures::{Poll, Sink, StartSend, Stream};
-use serde::{Deserialize, Serialize};
+use futures::{Async, Poll, Sink, StartSend, Stream};
+use tokio::codec::{Decoder, Encoder};
 use tokio_serde::{Deserializer, FramedRead, FramedWrite, Serializer};
+use zstd;
+use config::Config;
+use keyring::Keyring;
+use types::{Message, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 
-/// Bincode
+/// Compression
+
+/// Compresses the frames of a connection with zstd once the peer is known to
+/// accept compressed frames, leaving frames below the threshold as they are.
+/// Every frame advertises whether its sender accepts them, and both halves
+/// of a connection share what the peer last advertised.
+
+#[derive(Clone)]
+pub struct Compression {
+    enabled: bool,
+    threshold: usize,
+    peer_accepts: Arc<AtomicBool>,
+}
+
+impl Compression {
+
+    // A new connection, to a peer which may be known to accept compression
+    // from earlier connections
+    pub fn new(config: &Config, peer_accepts: bool) -> Compression {
+        Compression {
+            enabled: config.compression,
+            threshold: config.compression_threshold,
+            peer_accepts: Arc::new(AtomicBool::new(peer_accepts)),
+        }
+    }
+
+    // Compressed frames are refused as well as never sent
+    pub fn disabled() -> Compression {
+        Compression {
+            enabled: false,
+            threshold: 0,
+            peer_accepts: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn peer_accepts(&self) -> bool {
+        self.peer_accepts.load(Ordering::SeqCst)
+    }
+
+    fn applies(&self, len: usize) -> bool {
+        self.enabled && len >= self.threshold && self.peer_accepts.load(Ordering::SeqCst)
+    }
+}
+
+/// Envelope
+
+// The protocol version, message type and flags precede the message
+pub const HEADER_LEN: usize = 3;
+const FLAG_COMPRESSED: u8 = 1;
+const FLAG_ACCEPTS_COMPRESSION: u8 = 2;
+// The most a compressed frame may inflate to, as much as a length delimited
+// frame may hold
+const MAX_DECOMPRESSED_LEN: u64 = 8 * 1024 * 1024;
+
+fn decompress(payload: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut decompressed = vec![];
+    zstd::stream::read::Decoder::new(payload)?
+        .take(MAX_DECOMPRESSED_LEN + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame inflates beyond the limit"));
+    }
+    Ok(decompressed)
+}
+
+fn encode<T: Message>(item: &T, compression: &Compression) -> Result<Vec<u8>, io::Error> {
+    let payload = bincode::serialize(item)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let mut flags = 0;
+    if compression.enabled {
+        flags |= FLAG_ACCEPTS_COMPRESSION;
+    }
+    let payload = if compression.applies(payload.len()) {
+        flags |= FLAG_COMPRESSED;
+        zstd::stream::encode_all(&payload[..], 0)?
+    } else {
+        payload
+    };
+    let mut frame = vec![PROTOCOL_VERSION, item.kind(), flags];
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+// Yields nothing for message types unknown to this version
+fn decode<T: Message>(frame: &[u8], compression: &Compression) -> Result<Option<T>, io::Error> {
+    if frame.len() < HEADER_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too short"));
+    }
+    let (version, kind, flags) = (frame[0], frame[1], frame[2]);
+    compression.peer_accepts.store(flags & FLAG_ACCEPTS_COMPRESSION != 0, Ordering::SeqCst);
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported protocol version {}", version)));
+    }
+    if kind >= T::kinds() {
+        debug!("skipping unknown message type {} of version {}", kind, version);
+        return Ok(None);
+    }
+    let payload = &frame[HEADER_LEN..];
+    let item = if flags & FLAG_COMPRESSED != 0 {
+        if !compression.enabled {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "compressed frame not negotiated"));
+        }
+        bincode::deserialize(&decompress(payload)?)
+    } else {
+        bincode::deserialize(payload)
+    };
+    item.map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+/// Bincode, with each frame compressed as negotiated and sealed by the
+/// keyring
 
 struct Bincode<T> {
+    keyring: Keyring,
+    compression: Compression,
     ghost: PhantomData<T>,
 }
 
 pub struct BincodeReader<T, U> {
-    inner: FramedRead<T, U, Bincode<U>>,
+    inner: FramedRead<T, Option<U>, Bincode<U>>,
 }
 
 pub struct BincodeWriter<T: Sink, U> {
@@ -23,11 +142,11 @@ pub struct BincodeWriter<T: Sink, U> {
 impl<T, U> BincodeReader<T, U>
 where
     T: Stream<Error = io::Error>,
-    for<'a> U: Deserialize<'a>,
+    U: Message,
     BytesMut: From<T::Item>,
 {
-    pub fn new(inner: T) -> BincodeReader<T, U> {
-        let bincode = Bincode { ghost: PhantomData };
+    pub fn new(inner: T, keyring: Keyring, compression: Compression) -> BincodeReader<T, U> {
+        let bincode = Bincode { keyring, compression, ghost: PhantomData };
         BincodeReader {
             inner: FramedRead::new(inner, bincode),
         }
@@ -43,14 +162,20 @@ impl<T, U> BincodeReader<T, U> {
 impl<T, U> Stream for BincodeReader<T, U>
 where
     T: Stream<Error = io::Error>,
-    for<'a> U: Deserialize<'a>,
+    U: Message,
     BytesMut: From<T::Item>,
 {
     type Item = U;
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<U>, io::Error> {
-        self.inner.poll()
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Some(None) => continue,
+                None => return Ok(Async::Ready(None)),
+            }
+        }
     }
 }
 
@@ -77,10 +202,10 @@ where
 impl<T, U> BincodeWriter<T, U>
 where
     T: Sink<SinkItem = Bytes, SinkError = io::Error>,
-    U: Serialize,
+    U: Message,
 {
-    pub fn new(inner: T) -> BincodeWriter<T, U> {
-        let bincode = Bincode { ghost: PhantomData };
+    pub fn new(inner: T, keyring: Keyring, compression: Compression) -> BincodeWriter<T, U> {
+        let bincode = Bincode { keyring, compression, ghost: PhantomData };
         BincodeWriter {
             inner: FramedWrite::new(inner, bincode),
         }
@@ -96,7 +221,7 @@ impl<T: Sink, U> BincodeWriter<T, U> {
 impl<T, U> Sink for BincodeWriter<T, U>
 where
     T: Sink<SinkItem = Bytes, SinkError = io::Error>,
-    U: Serialize,
+    U: Message,
 {
     type SinkItem = U;
     type SinkError = io::Error;
@@ -126,24 +251,135 @@ where
     }
 }
 
-impl<T> Deserializer<T> for Bincode<T>
-where
-    for<'a> T: Deserialize<'a>,
-{
+impl<T: Message> Deserializer<Option<T>> for Bincode<T> {
     type Error = io::Error;
 
-    fn deserialize(&mut self, src: &BytesMut) -> Result<T, io::Error> {
-        bincode::deserialize(src)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    fn deserialize(&mut self, src: &BytesMut) -> Result<Option<T>, io::Error> {
+        let frame = self.keyring.open(src)?;
+        decode(&frame, &self.compression)
     }
 }
 
-impl<T: Serialize> Serializer<T> for Bincode<T> {
+impl<T: Message> Serializer<T> for Bincode<T> {
     type Error = io::Error;
 
     fn serialize(&mut self, item: &T) -> Result<Bytes, io::Error> {
-        bincode::serialize(item)
-            .map(Into::into)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        let frame = encode(item, &self.compression)?;
+        self.keyring.seal(&frame).map(Into::into)
+    }
+}
+
+/// Datagram, never compressed as there is no connection to negotiate over
+
+pub struct BincodeCodec<T> {
+    keyring: Keyring,
+    ghost: PhantomData<T>,
+}
+
+impl<T> BincodeCodec<T> {
+    pub fn new(keyring: Keyring) -> BincodeCodec<T> {
+        BincodeCodec { keyring, ghost: PhantomData }
+    }
+}
+
+impl<T: Message> Decoder for BincodeCodec<T> {
+    type Item = Option<T>;
+    type Error = io::Error;
+
+    // Each datagram holds exactly one item, yielding none ends the stream so
+    // unknown types are passed on as such
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Option<T>>, io::Error> {
+        let frame = self.keyring.open(&src[..]);
+        src.clear();
+        decode(&frame?, &Compression::disabled()).map(Some)
+    }
+}
+
+impl<T: Message> Encoder for BincodeCodec<T> {
+    type Item = T;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let frame = encode(&item, &Compression::disabled())?;
+        dst.extend_from_slice(&self.keyring.seal(&frame)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::{Datagram, Gossip, NetAddr, NodeId};
+
+    #[test]
+    fn test_envelope() {
+        let compression = Compression::disabled();
+        let mut frame = encode(&Datagram::Nack(7), &compression).unwrap();
+        match decode::<Datagram>(&frame, &compression).unwrap() {
+            Some(Datagram::Nack(seq)) => assert_eq!(seq, 7),
+            other => panic!("unexpected {:?}", other),
+        }
+        // a type added by a newer version is skipped
+        frame[1] = Datagram::kinds();
+        assert!(decode::<Datagram>(&frame, &compression).unwrap().is_none());
+        frame[0] = MIN_PROTOCOL_VERSION - 1;
+        assert!(decode::<Datagram>(&frame, &compression).is_err());
+    }
+
+    #[test]
+    fn test_negotiate_compression() {
+        let config = Config { compression: true, compression_threshold: 32, ..Config::default() };
+        let (client, server) = (Compression::new(&config, false), Compression::new(&config, false));
+        let large = Datagram::PingReq(1, NetAddr::new("127.0.0.1:1234".parse().unwrap()), NodeId::new("suspect"), NetAddr::new("127.0.0.1:1235".parse().unwrap()));
+        let gossip = Datagram::Ack(2, vec![]);
+
+        // nothing is compressed before the peer accepts it
+        let request = encode(&large, &client).unwrap();
+        assert_eq!(request[2] & FLAG_COMPRESSED, 0);
+        decode::<Datagram>(&request, &server).unwrap().unwrap();
+
+        let response = encode(&large, &server).unwrap();
+        assert_eq!(response[2] & FLAG_COMPRESSED, FLAG_COMPRESSED);
+        match decode::<Datagram>(&response, &client).unwrap() {
+            Some(Datagram::PingReq(seq, _, _, suspect_addr)) => {
+                assert_eq!(seq, 1);
+                assert_eq!(suspect_addr, NetAddr::new("127.0.0.1:1235".parse().unwrap()));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // frames below the threshold are left as they are
+        assert_eq!(encode(&gossip, &server).unwrap()[2] & FLAG_COMPRESSED, 0);
+
+        // a peer known to accept compression gets compressed requests
+        assert!(client.peer_accepts());
+        let request = encode(&large, &Compression::new(&config, true)).unwrap();
+        assert_eq!(request[2] & FLAG_COMPRESSED, FLAG_COMPRESSED);
+
+        // but compressed frames are refused where compression is disabled
+        assert!(decode::<Datagram>(&request, &Compression::disabled()).is_err());
+    }
+
+    #[test]
+    fn test_decompression_limit() {
+        let config = Config { compression: true, ..Config::default() };
+        let bomb = zstd::stream::encode_all(&vec![0u8; MAX_DECOMPRESSED_LEN as usize + 1][..], 0).unwrap();
+        let mut frame = vec![PROTOCOL_VERSION, 0, FLAG_COMPRESSED];
+        frame.extend_from_slice(&bomb);
+        assert!(decode::<Datagram>(&frame, &Compression::new(&config, false)).is_err());
+    }
+
+    #[test]
+    fn test_skip_unknown_gossip() {
+        let known = Gossip::Leave(NodeId::new("a"), 1);
+        // an ack as a newer version sends it, with a gossip type unknown here
+        let items = vec![(Gossip::kinds(), vec![1u8, 2, 3]), (known.kind(), bincode::serialize(&known).unwrap())];
+        let payload = bincode::serialize(&(Datagram::Ack(0, vec![]).kind() as u32, 7usize, items)).unwrap();
+        match bincode::deserialize::<Datagram>(&payload).unwrap() {
+            Datagram::Ack(seq, gossip_vec) => {
+                assert_eq!(seq, 7);
+                assert_eq!(gossip_vec, vec![known]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
     }
 }
@@ -1,69 +1,163 @@
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet};
+use std::cmp;
 use tokio::prelude::*;
 use tokio::timer::{self, delay_queue, DelayQueue};
-use types::NetAddr;
-use constants::ROUND_TRIP_TIME;
+use types::NodeId;
+use config::Config;
 
 pub struct Timeouts {
-    pub suspect_addr_vec: Vec<NetAddr>,
+    pub suspect_id_vec: Vec<NodeId>,
+}
+
+// A Lifeguard suspicion, starting at the maximum timeout and shrinking
+// towards the minimum as independent members confirm it.
+struct Suspicion {
+    key: delay_queue::Key,
+    start: Instant,
+    min: Duration,
+    max: Duration,
+    // The number of confirmations expected before reaching the minimum
+    k: usize,
+    suspecters: HashSet<NodeId>,
+}
+
+impl Suspicion {
+    fn new(key: delay_queue::Key, start: Instant, min: Duration, max: Duration, k: usize) -> Suspicion {
+        Suspicion { key, start, min, max, k, suspecters: HashSet::new() }
+    }
+
+    fn timeout(&self) -> Duration {
+        // the first suspecter does not count as a confirmation
+        let confirmations = self.suspecters.len().saturating_sub(1);
+        if self.k == 0 || confirmations >= self.k {
+            return self.min;
+        }
+        let frac = ((confirmations + 1) as f64).ln() / ((self.k + 1) as f64).ln();
+        let min = duration_ms(self.min);
+        let max = duration_ms(self.max);
+        let timeout = max - ((max - min) as f64 * frac).floor() as u64;
+        cmp::max(self.min, Duration::from_millis(timeout))
+    }
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
 }
 
 #[derive(Clone)]
 pub struct TimeoutCache {
+    config: Arc<Config>,
     // Tracks nodes suspected of failure
-    suspect_map: Arc<Mutex<HashMap<NetAddr, delay_queue::Key>>>,
-    suspect_timeouts: Arc<Mutex<DelayQueue<NetAddr>>>,
+    suspect_map: Arc<Mutex<HashMap<NodeId, Suspicion>>>,
+    suspect_timeouts: Arc<Mutex<DelayQueue<NodeId>>>,
 }
 
 impl TimeoutCache {
 
-    pub fn new() -> TimeoutCache {
+    pub fn new(config: Arc<Config>) -> TimeoutCache {
         TimeoutCache {
+            config,
             suspect_map: Arc::new(Mutex::new(HashMap::new())),
             suspect_timeouts: Arc::new(Mutex::new(DelayQueue::new())),
         }
     }
 
-    pub fn create_suspect_timeout(&self, suspect_addr: NetAddr) {
+    /// Arms a suspicion timeout scaled by the size of the cluster or, if the
+    /// suspect is already timing out, counts an independent confirmation and
+    /// shortens the deadline. Returns whether the suspecter was newly counted
+    /// while confirmations were still expected.
+    pub fn create_suspect_timeout(&self, suspect_id: NodeId, suspecter: NodeId, cluster_size: usize) -> bool {
         let mut suspect_timeouts = self.suspect_timeouts.lock().unwrap();
         let mut suspect_map = self.suspect_map.lock().unwrap();
-        match suspect_map.get(&suspect_addr) {
-            Some(_) =>
-                (),
-            None => {
-                let timeout_key = suspect_timeouts
-                    .insert(suspect_addr.clone(), Duration::from_millis(ROUND_TRIP_TIME));
-                suspect_map
-                    .insert(suspect_addr, timeout_key);
+        if let Some(suspicion) = suspect_map.get_mut(&suspect_id) {
+            if suspicion.suspecters.len() > suspicion.k || !suspicion.suspecters.insert(suspecter) {
+                return false;
             }
+            let deadline = cmp::max(suspicion.start + suspicion.timeout(), Instant::now());
+            debug!("suspect {:?} confirmed, timeout = {:?}", suspect_id, suspicion.timeout());
+            suspect_timeouts.reset_at(&suspicion.key, deadline);
+            return true;
         }
+
+        let n = cmp::max(cluster_size, 1) as f64;
+        let scale = n.log10().max(1.0);
+        let suspicion_mult = self.config.suspicion_mult;
+        let min_ms = (suspicion_mult as f64 * scale * self.config.protocol_period as f64) as u64;
+        let min = Duration::from_millis(min_ms);
+        let max = min * self.config.suspicion_max_mult;
+        // no more confirmations can be expected than there are other members
+        let k = cmp::min(suspicion_mult.saturating_sub(2), cluster_size.saturating_sub(2));
+        let timeout = if k == 0 { min } else { max };
+
+        let start = Instant::now();
+        let timeout_key = suspect_timeouts.insert_at(suspect_id.clone(), start + timeout);
+        let mut suspicion = Suspicion::new(timeout_key, start, min, max, k);
+        suspicion.suspecters.insert(suspecter);
+        suspect_map.insert(suspect_id, suspicion);
+        true
     }
 
-    pub fn remove_suspect_timeout(&self, suspect_addr: &NetAddr) {
-        let mut suspect_map = self.suspect_map.lock().unwrap();
+    pub fn remove_suspect_timeout(&self, suspect_id: &NodeId) {
         let mut suspect_timeouts = self.suspect_timeouts.lock().unwrap();
-        if let Some(expiration_key) = suspect_map.remove(suspect_addr) {
-            suspect_timeouts.remove(&expiration_key);
+        let mut suspect_map = self.suspect_map.lock().unwrap();
+        if let Some(suspicion) = suspect_map.remove(suspect_id) {
+            suspect_timeouts.remove(&suspicion.key);
         }
     }
 
     pub fn poll_purge(&self) -> Poll<Timeouts, timer::Error> {
-        let mut suspect_timeout_addr_vec = vec![];
+        let mut suspect_timeout_id_vec = vec![];
         let mut suspect_timeouts = self.suspect_timeouts.lock().unwrap();
         let mut suspect_map = self.suspect_map.lock().unwrap();
         while let Some(expired) = try_ready!(suspect_timeouts.poll()) {
-            let suspect_addr = expired.get_ref().clone();
-            warn!("suspect {:?} expired", suspect_addr);
-            let _ = suspect_map.remove(&suspect_addr).unwrap();
-            suspect_timeout_addr_vec.push(suspect_addr);
+            let suspect_id = expired.get_ref().clone();
+            warn!("suspect {:?} expired", suspect_id);
+            let _ = suspect_map.remove(&suspect_id).unwrap();
+            suspect_timeout_id_vec.push(suspect_id);
         }
 
         let timeouts = Timeouts {
-            suspect_addr_vec: suspect_timeout_addr_vec,
+            suspect_id_vec: suspect_timeout_id_vec,
         };
 
         Ok(Async::Ready(timeouts))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suspicion_timeout_shrinks() {
+        let mut queue = DelayQueue::new();
+        let key = queue.insert(NodeId::new("suspect"), Duration::from_secs(1));
+        let min = Duration::from_millis(1000);
+        let max = Duration::from_millis(6000);
+        let mut suspicion = Suspicion::new(key, Instant::now(), min, max, 3);
+
+        suspicion.suspecters.insert(NodeId::new("a"));
+        assert_eq!(suspicion.timeout(), max);
+        let mut previous = suspicion.timeout();
+        for name in &["b", "c", "d"] {
+            suspicion.suspecters.insert(NodeId::new(name));
+            assert!(suspicion.timeout() < previous);
+            previous = suspicion.timeout();
+        }
+        assert_eq!(suspicion.timeout(), min);
+        suspicion.suspecters.insert(NodeId::new("e"));
+        assert_eq!(suspicion.timeout(), min);
+    }
+
+    #[test]
+    fn test_duplicate_suspecter_is_ignored() {
+        let cache = TimeoutCache::new(Arc::new(Config::default()));
+        let suspect = NodeId::new("suspect");
+        assert!(cache.create_suspect_timeout(suspect.clone(), NodeId::new("a"), 10));
+        assert!(!cache.create_suspect_timeout(suspect.clone(), NodeId::new("a"), 10));
+        assert!(cache.create_suspect_timeout(suspect.clone(), NodeId::new("b"), 10));
+        assert_eq!(cache.suspect_map.lock().unwrap()[&suspect].suspecters.len(), 2);
+    }
+}
@@ -1,38 +1,94 @@
 use std::io;
+use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use futures::future::Either;
 use futures::sync::mpsc;
 use tokio::prelude::*;
 use tokio::net::TcpStream;
 use tokio::timer::timeout;
 use tokio;
 use bincode_channel;
+use bincode_codec::Compression;
+use config::Config;
+use keyring::Keyring;
+use tls::Tls;
 use types::{Request, Response};
 
-pub fn request(peer_addr: SocketAddr, req: Request, timeout: Duration) -> impl Future<Item = Response, Error = timeout::Error<io::Error>> {
-    let connect = TcpStream::connect(&peer_addr);
-    connect.and_then(|socket| {
-        let (read_half, write_half) = socket.split();
-        let writer = bincode_channel::new_writer::<Request>(write_half);
-        let reader = bincode_channel::new_reader::<Response>(read_half);
+/// Opens the TCP connections to other members, sealing frames with the
+/// keyring, compressing them as negotiated and wrapping the stream in TLS
+/// when configured. As each connection carries a single request, requests
+/// are only compressed for peers which accepted compression before.
 
-        let (tx, rx) = mpsc::unbounded();
+#[derive(Clone)]
+pub struct Connector {
+    config: Arc<Config>,
+    keyring: Keyring,
+    tls: Option<Tls>,
+    accepting: Arc<Mutex<HashSet<SocketAddr>>>,
+}
+
+impl Connector {
 
-        // client request writes request to the channel
-        let _ = tx.unbounded_send(req).unwrap();
+    pub fn new(config: Arc<Config>, keyring: Keyring, tls: Option<Tls>) -> Connector {
+        Connector { config, keyring, tls, accepting: Arc::new(Mutex::new(HashSet::new())) }
+    }
 
-        let write_request = writer.send_all(
-            rx.map_err(|_| {
-                io::Error::new(io::ErrorKind::Other, "[client] receive error")
+    pub fn request(&self, peer_addr: SocketAddr, req: Request, timeout: Duration) -> impl Future<Item = Response, Error = timeout::Error<io::Error>> {
+        let keyring = self.keyring.clone();
+        let accepting = self.accepting.clone();
+        let peer_accepts = accepting.lock().unwrap().contains(&peer_addr);
+        let compression = Compression::new(&self.config, peer_accepts);
+        let negotiated = compression.clone();
+        let tls = self.tls.clone();
+        let connect = TcpStream::connect(&peer_addr);
+        connect.and_then(move |socket| {
+            match tls {
+                Some(tls) =>
+                    Either::A(tls.connect(socket).and_then(move |socket| exchange(socket, req, keyring, compression))),
+                None =>
+                    Either::B(exchange(socket, req, keyring, compression)),
+            }
+        }).timeout(timeout).then(move |res| {
+            // a failed request may have been refused for its compression
+            let mut accepting = accepting.lock().unwrap();
+            if res.is_ok() && negotiated.peer_accepts() {
+                accepting.insert(peer_addr);
+            } else {
+                accepting.remove(&peer_addr);
+            }
+            res.and_then(|res| match res.into_iter().next() {
+                Some(response) => Ok(response),
+                // the peer closed the connection without answering
+                None => Err(timeout::Error::inner(io::Error::new(io::ErrorKind::UnexpectedEof, "[client] no response"))),
             })
-        ).then(|_| {
-            Ok(())
-        });
+        })
+    }
+}
+
+fn exchange<S>(socket: S, req: Request, keyring: Keyring, compression: Compression) -> impl Future<Item = Vec<Response>, Error = io::Error>
+    where
+      S: AsyncRead + AsyncWrite + Send + 'static
+{
+    let (read_half, write_half) = socket.split();
+    let writer = bincode_channel::new_writer::<_, Request>(write_half, keyring.clone(), compression.clone());
+    let reader = bincode_channel::new_reader::<_, Response>(read_half, keyring, compression);
+
+    let (tx, rx) = mpsc::unbounded();
+
+    // client request writes request to the channel
+    let _ = tx.unbounded_send(req).unwrap();
+
+    let write_request = writer.send_all(
+        rx.map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "[client] receive error")
+        })
+    ).then(|_| {
+        Ok(())
+    });
 
-        tokio::spawn(write_request);
+    tokio::spawn(write_request);
 
-        reader.take(1).collect()
-    }).timeout(timeout).and_then(|res| {
-        Ok(res[0].clone())
-    })
+    reader.take(1).collect()
 }
@@ -0,0 +1,236 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use toml;
+use dissemination;
+use types::{NodeId, UserEvent, Gossip, MAX_ID_LEN};
+
+/// The parameters of the Snowball family of consensus engines.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConsensusConfig {
+    // The fraction of the sample needed to sway a colour
+    pub alpha: f32,
+    // The number of consecutive successes before deciding
+    pub beta: u32,
+    // The number of peers sampled per round
+    pub k: usize,
+}
+
+impl Default for ConsensusConfig {
+    fn default() -> ConsensusConfig {
+        ConsensusConfig { alpha: 0.5, beta: 11, k: 4 }
+    }
+}
+
+/// The certificates securing TCP connections, the certificate of every
+/// member is issued by the authority for the shared server name.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    // The certificate authority of the cluster, in PEM
+    pub ca: PathBuf,
+    // The certificate chain and private key of this member, in PEM
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub server_name: String,
+}
+
+/// Tunes the protocol for the network it runs on. Times are in milliseconds.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub gossip_rate: usize,
+    pub protocol_period: u64,
+    pub round_trip_time: u64,
+    pub leave_timeout: u64,
+    pub push_pull_interval: u64,
+    pub tombstone_reap_time: u64,
+    pub ping_req_count: usize,
+    pub max_local_health: usize,
+    pub suspicion_mult: usize,
+    pub suspicion_max_mult: u32,
+    pub event_buffer_size: usize,
+    pub max_event_size: usize,
+    // The bytes of a probe datagram, the gossip piggybacked on it included,
+    // to be kept within the MTU
+    pub gossip_budget: usize,
+    // The members joined on startup, and whenever no other member is known,
+    // given as addresses, host names or SRV names
+    pub seeds: Vec<String>,
+    // A file listing further seeds, one per line
+    pub seed_file: Option<PathBuf>,
+    pub join_backoff_min: u64,
+    pub join_backoff_max: u64,
+    pub rejoin_interval: u64,
+    // Shared keys as 64 hex digits sealing all traffic, the first one is
+    // used for sealing, none leaves the traffic in plaintext
+    pub keys: Vec<String>,
+    // Wraps TCP connections in mutually authenticated TLS when given, which
+    // requires keys as well since datagrams are not covered by TLS
+    pub tls: Option<TlsConfig>,
+    // Compresses TCP frames of at least the threshold in bytes, once the
+    // peer has enabled compression too
+    pub compression: bool,
+    pub compression_threshold: usize,
+    pub consensus: ConsensusConfig,
+}
+
+impl Config {
+
+    /// Suited to members within a single local network.
+    pub fn lan() -> Config {
+        Config {
+            gossip_rate: 3,
+            protocol_period: 2000,
+            round_trip_time: 500,
+            leave_timeout: 10000,
+            push_pull_interval: 30000,
+            tombstone_reap_time: 300000,
+            ping_req_count: 3,
+            max_local_health: 8,
+            suspicion_mult: 4,
+            suspicion_max_mult: 6,
+            event_buffer_size: 512,
+            max_event_size: 512,
+            gossip_budget: 1024,
+            seeds: vec![],
+            seed_file: None,
+            join_backoff_min: 1000,
+            join_backoff_max: 60000,
+            rejoin_interval: 30000,
+            keys: vec![],
+            tls: None,
+            compression: false,
+            compression_threshold: 1024,
+            consensus: ConsensusConfig::default(),
+        }
+    }
+
+    /// Tolerates the latency and loss of members spread across the internet.
+    pub fn wan() -> Config {
+        Config {
+            gossip_rate: 4,
+            protocol_period: 5000,
+            round_trip_time: 3000,
+            leave_timeout: 30000,
+            push_pull_interval: 60000,
+            suspicion_mult: 6,
+            join_backoff_min: 5000,
+            join_backoff_max: 300000,
+            rejoin_interval: 60000,
+            ..Config::lan()
+        }
+    }
+
+    /// Detects failures quickly among members on the same host.
+    pub fn local() -> Config {
+        Config {
+            protocol_period: 1000,
+            round_trip_time: 200,
+            leave_timeout: 5000,
+            push_pull_interval: 15000,
+            suspicion_mult: 3,
+            join_backoff_min: 200,
+            join_backoff_max: 5000,
+            rejoin_interval: 5000,
+            ..Config::lan()
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Config> {
+        match name {
+            "lan" => Some(Config::lan()),
+            "wan" => Some(Config::wan()),
+            "local" => Some(Config::local()),
+            _ => None,
+        }
+    }
+
+    /// Overrides this configuration with the settings given in a TOML file,
+    /// leaving the settings the file omits untouched.
+    pub fn load<P: AsRef<Path>>(self, path: P) -> io::Result<Config> {
+        let input = fs::read_to_string(path)?;
+        self.merge_toml(&input)
+    }
+
+    pub fn merge_toml(self, input: &str) -> io::Result<Config> {
+        let overrides: toml::Value = input.parse().map_err(invalid_data)?;
+        let mut config = toml::Value::try_from(self).map_err(invalid_data)?;
+        merge(&mut config, overrides);
+        let config: Config = config.try_into().map_err(invalid_data)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Fails if a user event of the maximum size from a member of the
+    /// longest id could not be piggybacked on a probe within the budget.
+    pub fn validate(&self) -> io::Result<()> {
+        let event = UserEvent {
+            time: 0,
+            origin: NodeId::new(&"f".repeat(MAX_ID_LEN)),
+            name: String::new(),
+            payload: vec![0; self.max_event_size],
+        };
+        if !dissemination::fits_budget(self, &Gossip::Event(event)) {
+            let message = format!("max_event_size {} does not fit the gossip_budget {}", self.max_event_size, self.gossip_budget);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::lan()
+    }
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn merge(base: &mut toml::Value, overrides: toml::Value) {
+    match (base, overrides) {
+        (toml::Value::Table(base), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => {
+                        merge(existing, value);
+                        continue;
+                    }
+                    None => (),
+                }
+                base.insert(key, value);
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_toml() {
+        let input = "protocol_period = 1500\nseeds = [\"seed.local:1234\"]\n[consensus]\nk = 6\n";
+        let config = Config::wan().merge_toml(input).unwrap();
+        assert_eq!(config.protocol_period, 1500);
+        assert_eq!(config.seeds, vec!["seed.local:1234".to_string()]);
+        assert_eq!(config.round_trip_time, Config::wan().round_trip_time);
+        assert_eq!(config.consensus.k, 6);
+        assert_eq!(config.consensus.beta, ConsensusConfig::default().beta);
+    }
+
+    #[test]
+    fn test_validate_event_size() {
+        for config in &[Config::lan(), Config::wan(), Config::local()] {
+            config.validate().unwrap();
+        }
+        let input = "max_event_size = 1024\n";
+        assert_eq!(Config::lan().merge_toml(input).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+}
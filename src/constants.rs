@@ -1,4 +0,0 @@
-pub const GOSSIP_RATE: usize = 3;
-pub const PROTOCOL_PERIOD: u64 = 2000;
-pub const ROUND_TRIP_TIME: u64 = 500;
-
@@ -0,0 +1,275 @@
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+use std::collections::{HashMap, HashSet};
+use futures::future;
+use futures::sync::oneshot;
+use tokio::prelude::*;
+use tokio_threadpool;
+use trust_dns_resolver;
+use config::Config;
+
+/// A seed as configured, either a literal address, a host name resolved to
+/// its A/AAAA records or, prefixed by `srv:`, a name resolved to its SRV
+/// records.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Seed {
+    Addr(SocketAddr),
+    Host(String, u16),
+    Srv(String),
+}
+
+impl FromStr for Seed {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Seed> {
+        let s = s.trim();
+        if s.starts_with("srv:") {
+            return Ok(Seed::Srv(s["srv:".len()..].to_string()));
+        }
+        if let Ok(addr) = s.parse() {
+            return Ok(Seed::Addr(addr));
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid seed {:?}", s));
+        let colon = s.rfind(':').ok_or_else(|| invalid())?;
+        let port = s[colon + 1..].parse().map_err(|_| invalid())?;
+        Ok(Seed::Host(s[..colon].to_string(), port))
+    }
+}
+
+/// Looks up the addresses of seeds given by name. Lookups may block, they
+/// are run off the runtime.
+pub trait Resolver: Send + Sync {
+    fn lookup_host(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+    // The targets and ports of the SRV records of a name
+    fn lookup_srv(&self, name: &str) -> io::Result<Vec<(String, u16)>>;
+}
+
+/// Resolves through the system configuration.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup_host(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        (host, port).to_socket_addrs().map(|addrs| addrs.collect())
+    }
+
+    fn lookup_srv(&self, name: &str) -> io::Result<Vec<(String, u16)>> {
+        let resolver = trust_dns_resolver::Resolver::from_system_conf()?;
+        let lookup = resolver.lookup_srv(name)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        Ok(lookup.iter()
+            .map(|srv| (srv.target().to_utf8(), srv.port()))
+            .collect())
+    }
+}
+
+/// A resolver answering from fixed records, for tests.
+#[derive(Default)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    srv: HashMap<String, Vec<(String, u16)>>,
+}
+
+impl StaticResolver {
+
+    pub fn new() -> StaticResolver {
+        StaticResolver::default()
+    }
+
+    pub fn host(mut self, host: &str, ip: IpAddr) -> StaticResolver {
+        self.hosts.entry(host.to_string()).or_insert_with(Vec::new).push(ip);
+        self
+    }
+
+    pub fn srv(mut self, name: &str, target: &str, port: u16) -> StaticResolver {
+        self.srv.entry(name.to_string()).or_insert_with(Vec::new).push((target.to_string(), port));
+        self
+    }
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no records for {:?}", name))
+}
+
+impl Resolver for StaticResolver {
+    fn lookup_host(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self.hosts.get(host)
+            .map(|ips| ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect())
+            .ok_or_else(|| not_found(host))
+    }
+
+    fn lookup_srv(&self, name: &str) -> io::Result<Vec<(String, u16)>> {
+        self.srv.get(name).cloned().ok_or_else(|| not_found(name))
+    }
+}
+
+// A file listing one seed per line, reloaded whenever it is modified. The
+// file system may block, so it is only read off the reactor.
+struct SeedFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    seeds: Vec<Seed>,
+    // Whether the last attempt to read the file failed
+    failed: bool,
+}
+
+impl SeedFile {
+
+    fn new(path: PathBuf) -> SeedFile {
+        SeedFile { path, modified: None, seeds: vec![], failed: false }
+    }
+
+    // Returns whether the file changed since it was last read
+    fn reload(&mut self) -> bool {
+        let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+        if modified.is_some() && modified == self.modified {
+            return false;
+        }
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => {
+                info!("reading seeds from {:?}", self.path);
+                self.modified = modified;
+                self.seeds = parse_seeds(&contents);
+                self.failed = false;
+                true
+            }
+            Err(err) => {
+                // retried every period, so only the first failure is logged
+                if !self.failed {
+                    warn!("failed to read seed file {:?} => {:?}", self.path, err);
+                    self.failed = true;
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Parses a seed file, skipping blank lines and comments starting with `#`.
+pub fn parse_seeds(contents: &str) -> Vec<Seed> {
+    contents.lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| match line.parse() {
+            Ok(seed) => Some(seed),
+            Err(err) => {
+                warn!("skipping seed => {:?}", err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Finds the addresses of the seeds, resolving names anew on every attempt
+/// so that seeds may move.
+
+#[derive(Clone)]
+pub struct Discovery {
+    seeds: Vec<Seed>,
+    seed_file: Option<Arc<Mutex<SeedFile>>>,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl Discovery {
+
+    pub fn new(config: &Config, resolver: Arc<dyn Resolver>) -> Discovery {
+        let seeds = parse_seeds(&config.seeds.join("\n"));
+        let seed_file = config.seed_file.clone().map(|path| {
+            let mut seed_file = SeedFile::new(path);
+            seed_file.reload();
+            Arc::new(Mutex::new(seed_file))
+        });
+        Discovery { seeds, seed_file, resolver }
+    }
+
+    pub fn with_resolver(self, resolver: Arc<dyn Resolver>) -> Discovery {
+        Discovery { resolver, ..self }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seeds.is_empty() && self.seed_file.is_none()
+    }
+
+    /// Whether the seed file has been modified since it was last read, the
+    /// file is checked on a blocking section of the thread pool.
+    pub fn seed_file_changed(&self) -> impl Future<Item = bool, Error = ()> {
+        let seed_file = self.seed_file.clone();
+        future::poll_fn(move || {
+            tokio_threadpool::blocking(|| match seed_file {
+                Some(ref seed_file) => seed_file.lock().unwrap().reload(),
+                None => false,
+            })
+        }).map_err(|err| {
+            warn!("failed to check the seed file => {:?}", err)
+        })
+    }
+
+    /// Reads the seed file and resolves every seed on a thread of its own,
+    /// seeds which fail to resolve are skipped.
+    pub fn resolve(&self) -> impl Future<Item = Vec<SocketAddr>, Error = ()> {
+        let mut seeds = self.seeds.clone();
+        let seed_file = self.seed_file.clone();
+        let resolver = self.resolver.clone();
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            if let Some(seed_file) = seed_file {
+                let mut seed_file = seed_file.lock().unwrap();
+                seed_file.reload();
+                seeds.extend(seed_file.seeds.iter().cloned());
+            }
+            let _ = tx.send(resolve(&*resolver, &seeds));
+        });
+        rx.map_err(|_| ())
+    }
+}
+
+pub fn resolve(resolver: &dyn Resolver, seeds: &[Seed]) -> Vec<SocketAddr> {
+    let mut addrs = vec![];
+    for seed in seeds {
+        let resolved = match seed {
+            Seed::Addr(addr) =>
+                Ok(vec![*addr]),
+            Seed::Host(host, port) =>
+                resolver.lookup_host(host, *port),
+            Seed::Srv(name) =>
+                resolver.lookup_srv(name).map(|targets| {
+                    targets.into_iter()
+                        .filter_map(|(target, port)| resolver.lookup_host(&target, port).ok())
+                        .flat_map(|addrs| addrs)
+                        .collect()
+                }),
+        };
+        match resolved {
+            Ok(resolved) => addrs.extend(resolved),
+            Err(err) => warn!("failed to resolve seed {:?} => {:?}", seed, err),
+        }
+    }
+    // seeds may resolve to the same address in any order
+    let mut seen = HashSet::new();
+    addrs.retain(|addr| seen.insert(*addr));
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_seeds() {
+        let seeds = parse_seeds("127.0.0.1:1234\nseed.local:1235 # the seed\n\nsrv:_swim._tcp.local\nsrv:_missing\n127.0.0.1:1234");
+        assert_eq!(seeds.len(), 5);
+        let ip: IpAddr = "127.0.0.2".parse().unwrap();
+        let resolver = StaticResolver::new()
+            .host("seed.local", ip)
+            .host("node.local.", ip)
+            .srv("_swim._tcp.local", "node.local.", 1236);
+        let addrs: Vec<String> = resolve(&resolver, &seeds).iter().map(|addr| addr.to_string()).collect();
+        assert_eq!(addrs, vec!["127.0.0.1:1234", "127.0.0.2:1235", "127.0.0.2:1236"]);
+    }
+}
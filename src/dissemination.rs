@@ -1,20 +1,46 @@
 use std::sync::Arc;
+use bincode;
 use crossbeam_skiplist::SkipMap;
 use membership::Membership;
-use constants::GOSSIP_RATE;
-use types::{NetAddr, Gossip};
+use bincode_codec::HEADER_LEN;
+use config::Config;
+use keyring::SEAL_OVERHEAD;
+use types::{NodeId, NetAddr, Incarnation, Tags, UserEvent, Gossip, Datagram, SeqNo, MAX_ID_LEN, GOSSIP_HEADER_LEN};
 
 type GossipMap = SkipMap<Gossip, usize>;
 
+// The bytes of a probe datagram besides its gossip, that is the seal, the
+// envelope and a ping from the longest address to the longest id there is
+fn datagram_overhead() -> usize {
+    let addr = NetAddr::new("[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535".parse().unwrap());
+    let target_id = NodeId::new(&"f".repeat(MAX_ID_LEN));
+    let ping = Datagram::Ping(SeqNo::max_value(), addr, target_id, vec![]);
+    SEAL_OVERHEAD + HEADER_LEN + bincode::serialized_size(&ping).unwrap() as usize
+}
+
+/// The largest gossip item a probe datagram within the budget can carry.
+pub fn max_gossip_size(config: &Config) -> usize {
+    config.gossip_budget.saturating_sub(datagram_overhead() + GOSSIP_HEADER_LEN)
+}
+
+/// Whether a probe datagram within the budget can carry the gossip.
+pub fn fits_budget(config: &Config, gossip: &Gossip) -> bool {
+    bincode::serialized_size(gossip)
+        .map(|size| size as usize <= max_gossip_size(config))
+        .unwrap_or(false)
+}
+
 #[derive(Clone)]
 pub struct Dissemination {
+    config: Arc<Config>,
     gossip_map: Arc<GossipMap>,
 }
 
 impl Dissemination {
 
-    pub fn new() -> Dissemination {
+    pub fn new(config: Arc<Config>) -> Dissemination {
         Dissemination {
+            config,
             gossip_map: Arc::new(SkipMap::new()),
         }
     }
@@ -23,63 +49,179 @@ impl Dissemination {
         match self.gossip_map.get(&gossip) {
             Some(_) => (),
             None => {
+                self.invalidate(&gossip);
                 self.gossip_map.insert(gossip.clone(), 0);
             }
         }
     }
 
-    pub fn gossip_join(&self, peer_addr: NetAddr) {
-        let gossip = Gossip::Join(peer_addr);
+    // Gossip is only accepted once it has superseded the member state, so any
+    // older state change about the same member no longer needs disseminating.
+    // Independent suspicions of the same incarnation are kept as confirmations,
+    // and user events are independent of the member state altogether.
+    fn invalidate(&self, gossip: &Gossip) {
+        match gossip {
+            Gossip::Join(..) | Gossip::Event(..) => return,
+            _ => (),
+        }
+        for entry in self.gossip_map.iter() {
+            let stale = entry.key();
+            let superseded = match (stale, gossip) {
+                (Gossip::Join(..), _) | (Gossip::Event(..), _) =>
+                    false,
+                (Gossip::Suspect(_, i, _), Gossip::Suspect(_, j, _)) =>
+                    i != j,
+                _ =>
+                    true,
+            };
+            if superseded && stale.id() == gossip.id() {
+                entry.remove();
+            }
+        }
+    }
+
+    pub fn gossip_join(&self, peer_id: NodeId, peer_addr: NetAddr, incarnation: Incarnation, tags: Tags) {
+        let gossip = Gossip::Join(peer_id, peer_addr, incarnation, tags);
         self.try_gossip(gossip)
     }
 
-    pub fn gossip_alive(&self, peer_addr: NetAddr) {
-        let gossip = Gossip::Alive(peer_addr);
+    pub fn gossip_alive(&self, peer_id: NodeId, peer_addr: NetAddr, incarnation: Incarnation) {
+        let gossip = Gossip::Alive(peer_id, peer_addr, incarnation);
         self.try_gossip(gossip)
     }
 
-    pub fn gossip_suspect(&self, peer_addr: NetAddr) {
-        let gossip = Gossip::Suspect(peer_addr);
+    pub fn gossip_update(&self, peer_id: NodeId, incarnation: Incarnation, tags: Tags) {
+        let gossip = Gossip::Update(peer_id, incarnation, tags);
         self.try_gossip(gossip)
     }
 
-    pub fn gossip_confirm(&self, peer_addr: NetAddr) {
-        let gossip = Gossip::Confirm(peer_addr);
+    pub fn gossip_suspect(&self, peer_id: NodeId, incarnation: Incarnation, from: NodeId) {
+        let gossip = Gossip::Suspect(peer_id, incarnation, from);
         self.try_gossip(gossip)
     }
 
-    pub fn acquire_gossip<'a>(&'a self, membership: &'a Membership) -> Vec<Gossip> {
+    pub fn gossip_confirm(&self, peer_id: NodeId, incarnation: Incarnation) {
+        let gossip = Gossip::Confirm(peer_id, incarnation);
+        self.try_gossip(gossip)
+    }
+
+    pub fn gossip_leave(&self, peer_id: NodeId, incarnation: Incarnation) {
+        let gossip = Gossip::Leave(peer_id, incarnation);
+        self.try_gossip(gossip)
+    }
+
+    pub fn gossip_event(&self, event: UserEvent) {
+        let gossip = Gossip::Event(event);
+        self.try_gossip(gossip)
+    }
+
+    fn gossip_rate(&self, membership: &Membership) -> usize {
         let member_count = membership.len();
-        let gossip_rate = GOSSIP_RATE * (((member_count + 1) as f64).ln().ceil() as usize);
+        self.config.gossip_rate * (((member_count + 1) as f64).ln().ceil() as usize)
+    }
+
+    /// Whether a gossip has been retransmitted as often as the rate allows,
+    /// or there is nobody left to transmit it to.
+    pub fn is_disseminated(&self, gossip: &Gossip, membership: &Membership) -> bool {
+        if membership.len() == 0 {
+            return true;
+        }
+        match self.gossip_map.get(gossip) {
+            Some(entry) => *entry.value() > self.gossip_rate(membership),
+            None => true,
+        }
+    }
+
+    /// Selects the gossip to piggyback on a message, the least transmitted
+    /// first, for as long as the datagram carrying it fits the byte budget.
+    /// Gossip transmitted as often as the rate allows is dropped, as joins
+    /// and user events are never superseded.
+    pub fn acquire_gossip<'a>(&'a self, membership: &'a Membership) -> Vec<Gossip> {
+        let gossip_rate = self.gossip_rate(membership);
         debug!("gossip_rate = {:?}", gossip_rate);
+        let mut candidates: Vec<(Gossip, usize)> = vec![];
+        for entry in self.gossip_map.iter() {
+            if *entry.value() > gossip_rate {
+                entry.remove();
+            } else {
+                candidates.push((entry.key().clone(), *entry.value()));
+            }
+        }
+        candidates.sort_by_key(|&(_, dissemination_count)| dissemination_count);
+
         let mut gossip_vec = vec![];
-        if self.gossip_map.len() > 0 {
-            for entry in self.gossip_map.iter() {
-                let gossip = entry.key();
-                // Join messages are disseminated continuously until MINIMUM_MEMBERS has been
-                // reached.
-                let dissemination_count = entry.value();
-                if let Gossip::Join(_) = gossip {
-                    if *dissemination_count > gossip_rate {
-                        ()
-                    } else {
-                        debug!("dissemination_count({:?}), gossip_rate ({:?})",
-                              *dissemination_count, gossip_rate);
-                        self.gossip_map.insert(gossip.clone(), dissemination_count + 1);
-                        gossip_vec.push(gossip.clone())
-                    }
-                } else {
-                    if *dissemination_count > gossip_rate {
-                        ()
-                    } else {
-                        debug!("dissemination_count({:?}), gossip_rate ({:?})",
-                              *dissemination_count, gossip_rate);
-                        self.gossip_map.insert(gossip.clone(), dissemination_count + 1);
-                        gossip_vec.push(gossip.clone())
-                    }
-                }
+        let max_size = max_gossip_size(&self.config);
+        let mut remaining = self.config.gossip_budget.saturating_sub(datagram_overhead());
+        for (gossip, dissemination_count) in candidates {
+            let size = match bincode::serialized_size(&gossip) {
+                Ok(size) => size as usize,
+                Err(_) => continue,
+            };
+            // no datagram can carry it, so it is dropped rather than skipped
+            // on every probe
+            if size > max_size {
+                warn!("dropping gossip {:?} of {} bytes beyond the gossip budget", gossip, size);
+                self.gossip_map.remove(&gossip);
+                continue;
+            }
+            // a smaller gossip further on may still fit
+            let size = GOSSIP_HEADER_LEN + size;
+            if size > remaining {
+                continue;
             }
+            remaining -= size;
+            debug!("dissemination_count({:?}), gossip_rate ({:?})",
+                   dissemination_count, gossip_rate);
+            self.gossip_map.insert(gossip.clone(), dissemination_count + 1);
+            gossip_vec.push(gossip);
         }
-        return gossip_vec;
+        gossip_vec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire_gossip_within_budget() {
+        let gossip_size = GOSSIP_HEADER_LEN + bincode::serialized_size(&Gossip::Leave(NodeId::new("a"), 1)).unwrap() as usize;
+        let config = Config { gossip_rate: 1, gossip_budget: datagram_overhead() + gossip_size * 2, ..Config::default() };
+        let dissemination = Dissemination::new(Arc::new(config));
+        let membership = Membership::new();
+        membership.process_join(NodeId::new("b"), NetAddr::new("127.0.0.1:1234".parse().unwrap()), 1, Tags::new());
+
+        dissemination.gossip_leave(NodeId::new("a"), 1);
+        dissemination.gossip_leave(NodeId::new("c"), 1);
+        assert_eq!(dissemination.acquire_gossip(&membership).len(), 2);
+
+        // the fresh gossip goes before the gossip already transmitted
+        dissemination.gossip_leave(NodeId::new("d"), 1);
+        let gossip_vec = dissemination.acquire_gossip(&membership);
+        assert_eq!(gossip_vec.len(), 2);
+        assert_eq!(gossip_vec[0], Gossip::Leave(NodeId::new("d"), 1));
+    }
+
+    #[test]
+    fn test_disseminated_gossip_is_dropped() {
+        let dissemination = Dissemination::new(Arc::new(Config::default()));
+        let membership = Membership::new();
+        let event = UserEvent { time: 1, origin: NodeId::new("a"), name: "deploy".to_string(), payload: vec![] };
+        dissemination.gossip_event(event);
+        // with no members the rate is zero, so a single transmission suffices
+        assert_eq!(dissemination.acquire_gossip(&membership).len(), 1);
+        assert!(dissemination.acquire_gossip(&membership).is_empty());
+        assert_eq!(dissemination.gossip_map.len(), 0);
+    }
+
+    #[test]
+    fn test_oversize_gossip_is_dropped() {
+        let config = Config::default();
+        let dissemination = Dissemination::new(Arc::new(config.clone()));
+        let membership = Membership::new();
+        let payload = vec![0u8; config.gossip_budget];
+        dissemination.gossip_event(UserEvent { time: 1, origin: NodeId::new("a"), name: "deploy".to_string(), payload });
+        assert!(dissemination.acquire_gossip(&membership).is_empty());
+        assert_eq!(dissemination.gossip_map.len(), 0);
     }
 }
@@ -0,0 +1,94 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{BTreeMap, HashSet};
+use types::{NodeId, LamportTime, UserEvent};
+
+/// A Lamport clock, incremented for every event sent and advanced past the
+/// time of every event received.
+
+#[derive(Clone)]
+pub struct LamportClock {
+    counter: Arc<AtomicUsize>,
+}
+
+impl LamportClock {
+
+    pub fn new() -> LamportClock {
+        LamportClock {
+            counter: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn time(&self) -> LamportTime {
+        self.counter.load(Ordering::SeqCst)
+    }
+
+    pub fn increment(&self) -> LamportTime {
+        self.counter.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn witness(&self, time: LamportTime) {
+        let _ = self.counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+            if time >= current { Some(time + 1) } else { None }
+        });
+    }
+}
+
+/// Remembers the events seen within a window of recent Lamport times, so that
+/// each event is delivered once even though it is gossiped repeatedly.
+pub struct EventBuffer {
+    size: usize,
+    seen: BTreeMap<LamportTime, HashSet<NodeId>>,
+}
+
+impl EventBuffer {
+
+    pub fn new(size: usize) -> EventBuffer {
+        EventBuffer { size, seen: BTreeMap::new() }
+    }
+
+    /// Records an event given the current time, returning whether it is new.
+    /// Events older than the window are rejected, as they can no longer be
+    /// told apart from ones already delivered.
+    pub fn insert(&mut self, event: &UserEvent, now: LamportTime) -> bool {
+        let oldest = now.saturating_sub(self.size);
+        if event.time < oldest {
+            return false;
+        }
+        let expired: Vec<LamportTime> = self.seen.range(..oldest)
+            .map(|(time, _)| *time)
+            .collect();
+        for time in expired {
+            self.seen.remove(&time);
+        }
+        self.seen.entry(event.time)
+            .or_insert_with(HashSet::new)
+            .insert(event.origin.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(time: LamportTime, origin: &str) -> UserEvent {
+        UserEvent {
+            time,
+            origin: NodeId::new(origin),
+            name: "reload".to_string(),
+            payload: vec![],
+        }
+    }
+
+    #[test]
+    fn test_events_delivered_once() {
+        let clock = LamportClock::new();
+        clock.witness(9);
+        assert_eq!(clock.increment(), 11);
+        let mut buffer = EventBuffer::new(4);
+        assert!(buffer.insert(&event(10, "a"), clock.time()));
+        assert!(!buffer.insert(&event(10, "a"), clock.time()));
+        assert!(buffer.insert(&event(10, "b"), clock.time()));
+        assert!(!buffer.insert(&event(6, "c"), clock.time()));
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// The Lifeguard local health multiplier, a saturating score raised whenever
+/// this node has evidence that it is slow to process messages itself, such
+/// as missed acks, missed nacks or having to refute a suspicion.
+
+#[derive(Clone)]
+pub struct LocalHealth {
+    score: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl LocalHealth {
+
+    pub fn new(max: usize) -> LocalHealth {
+        LocalHealth {
+            score: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    pub fn score(&self) -> usize {
+        self.score.load(Ordering::SeqCst)
+    }
+
+    pub fn multiplier(&self) -> usize {
+        self.score() + 1
+    }
+
+    /// Scales a probe interval or timeout by the current multiplier.
+    pub fn scale(&self, duration: Duration) -> Duration {
+        duration * (self.multiplier() as u32)
+    }
+
+    pub fn degrade(&self) {
+        let max = self.max;
+        self.update(|score| if score < max { score + 1 } else { score });
+    }
+
+    pub fn improve(&self) {
+        self.update(|score| if score > 0 { score - 1 } else { score });
+    }
+
+    fn update<F: Fn(usize) -> usize>(&self, f: F) {
+        let _ = self.score.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |score| Some(f(score)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score_saturates() {
+        let health = LocalHealth::new(8);
+        health.improve();
+        assert_eq!(health.score(), 0);
+        for _ in 0..10 {
+            health.degrade();
+        }
+        assert_eq!(health.score(), 8);
+        assert_eq!(health.scale(Duration::from_millis(10)), Duration::from_millis(90));
+    }
+}
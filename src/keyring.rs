@@ -0,0 +1,165 @@
+use std::io;
+use std::sync::{Arc, RwLock};
+use ring::aead::{self, OpeningKey, SealingKey, CHACHA20_POLY1305};
+use ring::rand::{SecureRandom, SystemRandom};
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// The bytes a sealed frame adds to the plaintext.
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+pub type Key = [u8; KEY_LEN];
+
+/// The shared keys sealing every frame exchanged with other members with
+/// ChaCha20-Poly1305. Frames are sealed with the primary key and opened with
+/// whichever key fits, so that keys can be rotated across a running cluster
+/// by adding the new key to every member, making it primary everywhere and
+/// then removing the old one. An empty keyring leaves frames in plaintext.
+
+#[derive(Clone)]
+pub struct Keyring {
+    // The primary key comes first
+    keys: Arc<RwLock<Vec<Key>>>,
+    rng: Arc<SystemRandom>,
+}
+
+impl Keyring {
+
+    pub fn new(keys: Vec<Key>) -> Keyring {
+        Keyring {
+            keys: Arc::new(RwLock::new(keys)),
+            rng: Arc::new(SystemRandom::new()),
+        }
+    }
+
+    /// Parses hex encoded keys, the first of which is the primary.
+    pub fn from_hex(keys: &[String]) -> io::Result<Keyring> {
+        let keys = keys.iter()
+            .map(|key| parse_key(key))
+            .collect::<io::Result<Vec<Key>>>()?;
+        Ok(Keyring::new(keys))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.read().unwrap().is_empty()
+    }
+
+    /// Accepts frames sealed with the key, without sealing with it yet.
+    pub fn add_key(&self, key: Key) {
+        let mut keys = self.keys.write().unwrap();
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    /// Seals frames with the key from now on, adding it if needed.
+    pub fn use_key(&self, key: Key) {
+        let mut keys = self.keys.write().unwrap();
+        keys.retain(|existing| *existing != key);
+        keys.insert(0, key);
+    }
+
+    /// Stops accepting frames sealed with the key, the primary key cannot be
+    /// removed.
+    pub fn remove_key(&self, key: Key) -> io::Result<()> {
+        let mut keys = self.keys.write().unwrap();
+        if keys.first() == Some(&key) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot remove the primary key"));
+        }
+        keys.retain(|existing| *existing != key);
+        Ok(())
+    }
+
+    /// Seals a frame into its nonce followed by the ciphertext and tag.
+    pub fn seal(&self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let keys = self.keys.read().unwrap();
+        let primary = match keys.first() {
+            Some(primary) => primary,
+            None => return Ok(plaintext.to_vec()),
+        };
+        let key = SealingKey::new(&CHACHA20_POLY1305, primary).map_err(|_| crypto_error())?;
+        let mut frame = vec![0u8; NONCE_LEN];
+        self.rng.fill(&mut frame).map_err(|_| crypto_error())?;
+        frame.extend_from_slice(plaintext);
+        frame.extend(vec![0u8; TAG_LEN]);
+        let len = {
+            let (nonce, in_out) = frame.split_at_mut(NONCE_LEN);
+            aead::seal_in_place(&key, nonce, &[], in_out, TAG_LEN).map_err(|_| crypto_error())?
+        };
+        frame.truncate(NONCE_LEN + len);
+        Ok(frame)
+    }
+
+    /// Opens a frame with the first key that authenticates it.
+    pub fn open(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        let keys = self.keys.read().unwrap();
+        if keys.is_empty() {
+            return Ok(frame.to_vec());
+        }
+        if frame.len() < SEAL_OVERHEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too short"));
+        }
+        let (nonce, ciphertext) = frame.split_at(NONCE_LEN);
+        for key in keys.iter() {
+            let key = OpeningKey::new(&CHACHA20_POLY1305, key).map_err(|_| crypto_error())?;
+            let mut in_out = ciphertext.to_vec();
+            if let Ok(plaintext) = aead::open_in_place(&key, nonce, &[], 0, &mut in_out) {
+                return Ok(plaintext.to_vec());
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData, "no key opens the frame"))
+    }
+}
+
+impl Default for Keyring {
+    fn default() -> Keyring {
+        Keyring::new(vec![])
+    }
+}
+
+fn crypto_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "crypto failure")
+}
+
+/// Parses a key given as 64 hex digits.
+pub fn parse_key(hex: &str) -> io::Result<Key> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid key, expected {} hex digits", KEY_LEN * 2));
+    let hex = hex.trim();
+    if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate_keys() {
+        let old = [1u8; KEY_LEN];
+        let new = [2u8; KEY_LEN];
+        let sender = Keyring::new(vec![old]);
+        let receiver = Keyring::new(vec![old]);
+
+        let frame = sender.seal(b"ping").unwrap();
+        assert!(frame != b"ping".to_vec());
+        assert_eq!(receiver.open(&frame).unwrap(), b"ping".to_vec());
+
+        // the receiver accepts the new key before the sender uses it
+        receiver.add_key(new);
+        sender.use_key(new);
+        let frame = sender.seal(b"ping").unwrap();
+        assert_eq!(receiver.open(&frame).unwrap(), b"ping".to_vec());
+
+        receiver.use_key(new);
+        receiver.remove_key(old).unwrap();
+        assert!(receiver.remove_key(new).is_err());
+        assert!(receiver.open(&Keyring::new(vec![old]).seal(b"ping").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+extern crate bincode;
+extern crate bytes;
+extern crate colored;
+extern crate crossbeam;
+extern crate crossbeam_skiplist;
+#[macro_use]
+extern crate futures;
+#[macro_use]
+extern crate log;
+extern crate rand;
+extern crate ring;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate tokio;
+extern crate tokio_rustls;
+extern crate tokio_serde;
+extern crate tokio_threadpool;
+extern crate toml;
+extern crate trust_dns_resolver;
+extern crate zstd;
+
+mod bincode_codec;
+mod bincode_channel;
+mod cache;
+mod client;
+pub mod config;
+mod digraph;
+pub mod discovery;
+mod dissemination;
+mod event;
+mod health;
+pub mod keyring;
+pub mod membership;
+pub mod node;
+pub mod protocol;
+pub mod query;
+pub mod server;
+pub mod swim;
+pub mod types;
+pub mod tls;
+mod udp;
+
+pub use config::{Config, ConsensusConfig, TlsConfig};
+pub use discovery::{Resolver, StaticResolver, SystemResolver};
+pub use keyring::Keyring;
+pub use membership::{Membership, MemberEvent, MemberState, State};
+pub use node::{Node, NodeBuilder};
+pub use protocol::slush::Slush;
+pub use protocol::snowflake::Snowflake;
+pub use protocol::snowball::Snowball;
+pub use query::{QueryFilter, QueryResponse};
+pub use server::Server;
+pub use swim::Swim;
+pub use types::{NodeId, NetAddr, Tags, UserEvent};
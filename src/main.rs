@@ -1,40 +1,26 @@
-extern crate bincode;
-extern crate bytes;
-extern crate colored;
 extern crate clap;
-extern crate crossbeam;
-extern crate crossbeam_skiplist;
-#[macro_use]
 extern crate futures;
 #[macro_use]
 extern crate log;
 extern crate pretty_env_logger;
-extern crate rand;
-#[macro_use]
-extern crate serde_derive;
-extern crate serde;
-extern crate tokio;
-extern crate tokio_serde;
+extern crate swim;
+extern crate tokio_signal;
 
-mod bincode_codec;
-mod bincode_channel;
-mod cache;
-mod client;
-mod constants;
-mod digraph;
-mod dissemination;
-mod membership;
-mod protocol;
-mod server;
-mod swim;
-mod types;
-
-use std::sync::{Arc, Mutex};
 use std::net::SocketAddr;
 use clap::{Arg, App};
-use server::Server;
-use swim::Swim;
-use protocol::snowball::Snowball;
+use futures::prelude::*;
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+use swim::{Config, NodeBuilder, NodeId};
+
+// Resolves on the first SIGINT or SIGTERM
+fn shutdown_signal() -> impl Future<Item = (), Error = ()> {
+    let interrupt = Signal::new(SIGINT).flatten_stream();
+    let terminate = Signal::new(SIGTERM).flatten_stream();
+    interrupt.select(terminate)
+        .into_future()
+        .map(|_| ())
+        .map_err(|_| ())
+}
 
 fn main() {
     pretty_env_logger::init();
@@ -48,11 +34,60 @@ fn main() {
              .help("The address of this node")
              .required(true)
              .takes_value(true))
+        .arg(Arg::with_name("name")
+             .short("n")
+             .long("name")
+             .value_name("NAME")
+             .help("The unique name of this node, a random UUID by default")
+             .takes_value(true))
+        .arg(Arg::with_name("tag")
+             .short("t")
+             .long("tag")
+             .value_name("KEY=VALUE")
+             .help("A tag describing this node, may be repeated")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
         .arg(Arg::with_name("bootstrap")
              .short("b")
              .long("bootstrap")
-             .value_name("IP:PORT")
-             .help("The address of a seed to bootstrap to")
+             .value_name("SEED")
+             .help("A seed to bootstrap to as IP:PORT, HOST:PORT or srv:NAME, may be repeated")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+        .arg(Arg::with_name("seed-file")
+             .long("seed-file")
+             .value_name("FILE")
+             .help("A file listing seeds one per line, read again whenever it changes")
+             .takes_value(true))
+        .arg(Arg::with_name("preset")
+             .long("preset")
+             .value_name("PRESET")
+             .help("The network the protocol is tuned for")
+             .possible_values(&["lan", "wan", "local"])
+             .default_value("lan")
+             .takes_value(true))
+        .arg(Arg::with_name("config")
+             .short("c")
+             .long("config")
+             .value_name("FILE")
+             .help("A TOML file overriding settings of the preset")
+             .takes_value(true))
+        .arg(Arg::with_name("gossip-rate")
+             .long("gossip-rate")
+             .value_name("N")
+             .help("The number of times gossip is retransmitted, scaled by the cluster size")
+             .takes_value(true))
+        .arg(Arg::with_name("protocol-period")
+             .long("protocol-period")
+             .value_name("MS")
+             .help("The interval between probes")
+             .takes_value(true))
+        .arg(Arg::with_name("round-trip-time")
+             .long("round-trip-time")
+             .value_name("MS")
+             .help("The time a probe waits for an ack")
              .takes_value(true))
         .arg(Arg::with_name("delay")
              .short("d")
@@ -65,20 +100,49 @@ fn main() {
     let bind_addr: SocketAddr = matches.value_of("address").unwrap()
         .parse().unwrap();
 
-    let delay: Option<u64> = match matches.value_of("delay") {
-        Some(s) => Some(s.parse().unwrap()),
-        None => None,
-    };
+    let mut config = Config::preset(matches.value_of("preset").unwrap()).unwrap();
+    if let Some(path) = matches.value_of("config") {
+        config = config.load(path).expect("invalid configuration file");
+    }
+    if let Some(seed_file) = matches.value_of("seed-file") {
+        config.seed_file = Some(seed_file.into());
+    }
+    if let Some(gossip_rate) = matches.value_of("gossip-rate") {
+        config.gossip_rate = gossip_rate.parse().unwrap();
+    }
+    if let Some(protocol_period) = matches.value_of("protocol-period") {
+        config.protocol_period = protocol_period.parse().unwrap();
+    }
+    if let Some(round_trip_time) = matches.value_of("round-trip-time") {
+        config.round_trip_time = round_trip_time.parse().unwrap();
+    }
+
+    let mut builder = NodeBuilder::new(bind_addr)
+        .config(config);
+
+    if let Some(delay) = matches.value_of("delay") {
+        builder = builder.delay(delay.parse().unwrap());
+    }
 
-    let swim = Swim::new(bind_addr.clone(), delay);
-    let snowball = Arc::new(Mutex::new(Snowball::new(swim.addr.clone())));
-    let server = Server::new(bind_addr.clone(), swim.clone(), snowball.clone());
-    server.clone().spawn();
+    if let Some(name) = matches.value_of("name") {
+        builder = builder.id(NodeId::new(name));
+    }
 
-    if let Some(bootstrap_addr) = matches.value_of("bootstrap") {
-        let bootstrap_addr: SocketAddr = bootstrap_addr.parse().unwrap();
-        server.bootstrap(bootstrap_addr);
+    for tag in matches.values_of("tag").into_iter().flat_map(|values| values) {
+        let mut parts = tag.splitn(2, '=');
+        let key = parts.next().unwrap();
+        let value = parts.next().expect("tags are given as KEY=VALUE");
+        builder = builder.tag(key, value);
     }
 
-    swim.run(snowball);
+    for seed in matches.values_of("bootstrap").into_iter().flat_map(|values| values) {
+        builder = builder.seed(seed);
+    }
+
+    let node = builder.start().expect("failed to start node");
+
+    // Leave the cluster gracefully on shutdown
+    let _ = shutdown_signal().wait();
+    info!("shutting down");
+    node.leave();
 }
@@ -1,162 +1,564 @@
+use std::cmp;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::collections::HashSet;
+use futures::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
 use crossbeam_skiplist::SkipMap;
 use crossbeam_skiplist::map::Entry;
 use crossbeam::deque::{self, Worker, Stealer, Steal};
 use rand::{self, Rng};
-use types::NetAddr;
+use types::{NodeId, NetAddr, Incarnation, Tags};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum State {
     Alive,
     Suspected,
+    // Tombstones, kept until reaped so that stale gossip cannot resurrect them
+    Dead,
+    Left,
 }
 
-type MembershipMap = SkipMap<NetAddr, State>;
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub addr: NetAddr,
+    pub state: State,
+    pub incarnation: Incarnation,
+    pub tags: Tags,
+    pub since: Instant,
+}
+
+impl Member {
+    pub fn new(addr: NetAddr, state: State, incarnation: Incarnation, tags: Tags) -> Member {
+        Member { addr, state, incarnation, tags, since: Instant::now() }
+    }
+
+    pub fn is_live(&self) -> bool {
+        match self.state {
+            State::Alive | State::Suspected => true,
+            State::Dead | State::Left => false,
+        }
+    }
+}
+
+/// A member as exchanged during a push-pull state synchronisation.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberState {
+    pub id: NodeId,
+    pub addr: NetAddr,
+    pub state: State,
+    pub incarnation: Incarnation,
+    pub tags: Tags,
+}
+
+/// A change in the state of a member, carrying the member as it is after
+/// the change.
+
+#[derive(Debug, Clone)]
+pub enum MemberEvent {
+    Joined(MemberState),
+    Updated(MemberState),
+    Suspected(MemberState),
+    // A suspected member that refuted the suspicion
+    Recovered(MemberState),
+    Failed(MemberState),
+    Left(MemberState),
+    // A tombstone that has been forgotten
+    Reaped(NodeId),
+}
+
+fn member_state(id: &NodeId, member: &Member) -> MemberState {
+    MemberState {
+        id: id.clone(),
+        addr: member.addr.clone(),
+        state: member.state.clone(),
+        incarnation: member.incarnation,
+        tags: member.tags.clone(),
+    }
+}
+
+// The events describing the change from one member state to the next
+fn transition(id: &NodeId, previous: Option<&Member>, next: &Member) -> Vec<MemberEvent> {
+    let state = member_state(id, next);
+    match next.state {
+        State::Alive => match previous {
+            Some(previous) if previous.is_live() => {
+                let mut events = vec![];
+                if previous.state == State::Suspected {
+                    events.push(MemberEvent::Recovered(state.clone()));
+                }
+                if previous.addr != next.addr || previous.tags != next.tags {
+                    events.push(MemberEvent::Updated(state));
+                }
+                events
+            }
+            _ => vec![MemberEvent::Joined(state)],
+        },
+        State::Suspected => vec![MemberEvent::Suspected(state)],
+        State::Dead => vec![MemberEvent::Failed(state)],
+        State::Left => vec![MemberEvent::Left(state)],
+    }
+}
+
+type MembershipMap = SkipMap<NodeId, Member>;
 
 #[derive(Clone)]
 pub struct Membership {
     elements: Arc<MembershipMap>,
-    swim_ordering_worker: Arc<Mutex<Worker<NetAddr>>>,
-    swim_ordering_stealer: Arc<Stealer<NetAddr>>,
-    proto_ordering_worker: Arc<Mutex<Worker<NetAddr>>>,
-    proto_ordering_stealer: Arc<Stealer<NetAddr>>,
+    swim_ordering_worker: Arc<Mutex<Worker<NodeId>>>,
+    swim_ordering_stealer: Arc<Stealer<NodeId>>,
+    proto_ordering_worker: Arc<Mutex<Worker<NodeId>>>,
+    proto_ordering_stealer: Arc<Stealer<NodeId>>,
+    subscribers: Arc<Mutex<Vec<UnboundedSender<MemberEvent>>>>,
 }
 
 impl Membership {
 
     pub fn new() -> Membership {
-        let (swim_worker, swim_stealer) = deque::lifo::<NetAddr>();
-        let (proto_worker, proto_stealer) = deque::lifo::<NetAddr>();
+        let (swim_worker, swim_stealer) = deque::lifo::<NodeId>();
+        let (proto_worker, proto_stealer) = deque::lifo::<NodeId>();
         Membership {
             elements: Arc::new(SkipMap::new()),
             swim_ordering_worker: Arc::new(Mutex::new(swim_worker)),
             swim_ordering_stealer: Arc::new(swim_stealer),
             proto_ordering_worker: Arc::new(Mutex::new(proto_worker)),
             proto_ordering_stealer: Arc::new(proto_stealer),
+            subscribers: Arc::new(Mutex::new(vec![])),
         }
     }
 
+    /// Returns a stream of every member state transition from now on.
+    pub fn subscribe(&self) -> UnboundedReceiver<MemberEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn emit(&self, event: MemberEvent) {
+        // subscribers which dropped their receiver are forgotten
+        self.subscribers.lock().unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    fn insert(&self, id: NodeId, member: Member, previous: Option<&Member>) {
+        let events = transition(&id, previous, &member);
+        self.elements.insert(id, member);
+        for event in events {
+            self.emit(event);
+        }
+    }
+
+    /// The number of live members, tombstones are not counted.
     pub fn len(&self) -> usize {
-        self.elements.len()
+        self.elements.iter()
+            .filter(|entry| entry.value().is_live())
+            .count()
+    }
+
+    pub fn is_live(&self, id: &NodeId) -> bool {
+        match self.get(id) {
+            Some(entry) => entry.value().is_live(),
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: &NodeId) -> Option<Entry<NodeId, Member>> {
+        self.elements.get(id)
+    }
+
+    pub fn snapshot(&self) -> Vec<MemberState> {
+        self.elements.iter()
+            .map(|entry| member_state(entry.key(), entry.value()))
+            .collect()
     }
 
-    pub fn get(&self, addr: &NetAddr) -> Option<Entry<NetAddr, State>> {
-        self.elements.get(addr)
+    pub fn addr(&self, id: &NodeId) -> Option<NetAddr> {
+        self.get(id).map(|entry| entry.value().addr.clone())
     }
 
-    pub fn remove(&self, addr: &NetAddr) {
-        match self.get(addr) {
-            Some(_) => {
-                self.elements.remove(addr).unwrap();
+    pub fn incarnation(&self, id: &NodeId) -> Option<Incarnation> {
+        self.get(id).map(|entry| entry.value().incarnation)
+    }
+
+    /// Marks a member as alive at a possibly new address if the incarnation
+    /// is newer than the one held, returning whether the update was applied.
+    /// A newer incarnation also revives a tombstone, as only a restarted
+    /// member can have one.
+    pub fn alive(&self, id: NodeId, addr: NetAddr, incarnation: Incarnation) -> bool {
+        match self.get(&id) {
+            Some(entry) => {
+                let member = entry.value();
+                if incarnation > member.incarnation {
+                    info!("setting state {:?} to alive", member);
+                    let tags = member.tags.clone();
+                    self.insert(id, Member::new(addr, State::Alive, incarnation, tags), Some(member));
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false
+        }
+    }
+
+    /// Marks a member as suspected, a suspicion overrides an alive state of
+    /// the same incarnation but only a newer incarnation overrides a suspicion.
+    pub fn suspect(&self, id: NodeId, incarnation: Incarnation) -> bool {
+        match self.get(&id) {
+            Some(entry) => {
+                let member = entry.value();
+                let overrides = match member.state {
+                    State::Alive => incarnation >= member.incarnation,
+                    State::Suspected => incarnation > member.incarnation,
+                    State::Dead | State::Left => false,
+                };
+                if overrides {
+                    info!("setting state {:?} to suspect", member);
+                    let addr = member.addr.clone();
+                    let tags = member.tags.clone();
+                    self.insert(id, Member::new(addr, State::Suspected, incarnation, tags), Some(member));
+                    true
+                } else {
+                    false
+                }
             }
-            None =>
-                warn!("attempt to remove non-existent entry"),
+            None => false
         }
     }
 
-    pub fn alive(&self, addr: NetAddr) {
-        match self.get(&addr) {
+    pub fn is_suspected(&self, id: &NodeId, incarnation: Incarnation) -> bool {
+        match self.get(id) {
             Some(entry) => {
-                let state = entry.value();
-                info!("setting state {:?} to alive", state);
-                self.elements.insert(addr, State::Alive);
+                let member = entry.value();
+                member.state == State::Suspected && member.incarnation == incarnation
             }
-            None => ()
+            None => false
         }
     }
 
-    pub fn suspect(&self, addr: NetAddr) {
-        match self.get(&addr) {
+    /// A confirmed failure overrides every other state regardless of
+    /// incarnation, leaving a tombstone. Returns whether the member was live.
+    pub fn confirm(&self, id: &NodeId, incarnation: Incarnation) -> bool {
+        self.bury(id, incarnation, State::Dead)
+    }
+
+    /// Marks a member which announced that it is leaving as left, unless the
+    /// announcement predates its current incarnation.
+    pub fn leave(&self, id: &NodeId, incarnation: Incarnation) -> bool {
+        self.bury(id, incarnation, State::Left)
+    }
+
+    fn bury(&self, id: &NodeId, incarnation: Incarnation, state: State) -> bool {
+        match self.get(id) {
             Some(entry) => {
-                let state = entry.value();
-                info!("setting state {:?} to suspect", state);
-                self.elements.insert(addr, State::Suspected);
+                let member = entry.value();
+                let overrides = match state {
+                    State::Dead => true,
+                    _ => incarnation >= member.incarnation,
+                };
+                if member.is_live() && overrides {
+                    info!("setting state {:?} to {:?}", member, state);
+                    let addr = member.addr.clone();
+                    let tags = member.tags.clone();
+                    // only a newer incarnation than any seen may rejoin
+                    let incarnation = cmp::max(incarnation, member.incarnation);
+                    self.insert(id.clone(), Member::new(addr, state, incarnation, tags), Some(member));
+                    true
+                } else {
+                    false
+                }
             }
-            None => ()
+            None => false
         }
     }
 
-    pub fn process_join(&self, peer_addr: NetAddr) -> bool {
-        match self.get(&peer_addr) {
-            Some(_) =>
-                false,
+    /// Accepts a new member, or a known member rejoining with a newer
+    /// incarnation, such as after a restart or a change of address.
+    pub fn process_join(&self, id: NodeId, addr: NetAddr, incarnation: Incarnation, tags: Tags) -> bool {
+        match self.get(&id) {
+            Some(entry) => {
+                let member = entry.value();
+                if incarnation > member.incarnation {
+                    info!("member {:?} rejoined at {:?} with incarnation {:?}", id, addr, incarnation);
+                    self.insert(id.clone(), Member::new(addr, State::Alive, incarnation, tags), Some(member));
+                    true
+                } else {
+                    false
+                }
+            }
             None => {
-                self.elements.insert(peer_addr, State::Alive);
+                self.insert(id, Member::new(addr, State::Alive, incarnation, tags), None);
                 true
             }
         }
     }
 
+    /// Replaces the tags of a live member. Tags are only ever changed by the
+    /// member itself along with its incarnation, so the tags of the current
+    /// incarnation are accepted too, such as when they arrive after an alive
+    /// message of the same incarnation. Only a newer incarnation revives a
+    /// suspected member, tags of the same incarnation leave the state as is.
+    pub fn update(&self, id: NodeId, incarnation: Incarnation, tags: Tags) -> bool {
+        match self.get(&id) {
+            Some(entry) => {
+                let member = entry.value();
+                let newer = incarnation > member.incarnation ||
+                    (incarnation == member.incarnation && tags != member.tags);
+                if member.is_live() && newer {
+                    info!("updating tags of {:?} to {:?}", id, tags);
+                    let addr = member.addr.clone();
+                    let state = if incarnation > member.incarnation {
+                        State::Alive
+                    } else {
+                        member.state.clone()
+                    };
+                    self.insert(id, Member::new(addr, state, incarnation, tags), Some(member));
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false
+        }
+    }
+
+    pub fn tags(&self, id: &NodeId) -> Option<Tags> {
+        self.get(id).map(|entry| entry.value().tags.clone())
+    }
+
+    /// The live members tagged with the given key and value.
+    pub fn with_tag(&self, key: &str, value: &str) -> Vec<NodeId> {
+        self.elements.iter()
+            .filter(|entry| {
+                let member = entry.value();
+                member.is_live() && member.tags.get(key).map(|v| v.as_str()) == Some(value)
+            })
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Removes tombstones older than the reap time, after which the member
+    /// is forgotten entirely.
+    pub fn reap(&self, reap_time: Duration) {
+        for entry in self.elements.iter() {
+            let member = entry.value();
+            if !member.is_live() && member.since.elapsed() >= reap_time {
+                debug!("reaping {:?} {:?}", entry.key(), member);
+                entry.remove();
+                self.emit(MemberEvent::Reaped(entry.key().clone()));
+            }
+        }
+    }
+
+    /// Picks up to count alive members uniformly at random, without the
+    /// round robin ordering used by the protocol period.
+    pub fn sample_random(&self, count: usize, exclude: Vec<NodeId>) -> Vec<NodeId> {
+        let mut ids: Vec<NodeId> = self.elements.iter()
+            .filter(|entry| entry.value().state == State::Alive)
+            .map(|entry| entry.key().clone())
+            .filter(|id| !exclude.contains(id))
+            .collect();
+        let mut rng = rand::thread_rng();
+        rng.shuffle(&mut ids);
+        ids.truncate(count);
+        ids
+    }
+
     // TODO Improve sampling functions
 
-    pub fn sample_rr(&self, count: usize, exclude: Vec<NetAddr>) -> Vec<NetAddr> {
-        assert!(count < self.elements.len());
+    pub fn sample_rr(&self, count: usize, exclude: Vec<NodeId>) -> Vec<NodeId> {
+        assert!(count < self.len());
 
         // drain count keys from the current ordering
-        let mut addrs = vec![];
-        while addrs.len() < count {
-            if let Steal::Data(addr) = self.swim_ordering_stealer.steal() {
-                addrs.push(addr)
+        let mut ids = vec![];
+        while ids.len() < count {
+            if let Steal::Data(id) = self.swim_ordering_stealer.steal() {
+                // the ordering may predate a member's death
+                if self.is_live(&id) {
+                    ids.push(id)
+                }
             } else {
                 // Regenerate ordering
                 let mut members = vec![];
                 for entry in self.elements.iter() {
-                    if !exclude.contains(entry.key()) {
+                    if entry.value().is_live() && !exclude.contains(entry.key()) {
                         members.push(entry)
                     }
                 }
+                if members.is_empty() {
+                    break;
+                }
 
                 // Pick random indices linearly (slow)
                 let mut rng = rand::thread_rng();
                 let mut indices: HashSet<usize> = HashSet::new();
-                while indices.len() < self.elements.len() {
-                    let r: usize = rng.gen_range(0, self.elements.len());
+                while indices.len() < members.len() {
+                    let r: usize = rng.gen_range(0, members.len());
                     indices.insert(r);
                 }
 
                 for i in indices.iter().cloned() {
-                    let addr = members[i].key();
+                    let id = members[i].key();
                     self.swim_ordering_worker.lock().unwrap()
-                        .push(addr.clone());
+                        .push(id.clone());
                 }
             }
         }
-        addrs
+        ids
     }
 
     pub fn sample(&self, count: usize, exclude: Vec<NetAddr>) -> Vec<NetAddr> {
-        assert!(count <= self.elements.len());
+        assert!(count <= self.len());
 
         // drain count keys from the current ordering
         let mut addrs = vec![];
         while addrs.len() < count {
-            if let Steal::Data(addr) = self.proto_ordering_stealer.steal() {
-                addrs.push(addr)
+            if let Steal::Data(id) = self.proto_ordering_stealer.steal() {
+                // the ordering may predate a member's death
+                if let Some(entry) = self.get(&id) {
+                    if entry.value().is_live() {
+                        addrs.push(entry.value().addr.clone())
+                    }
+                }
             } else {
                 // Regenerate ordering
                 let mut members = vec![];
                 for entry in self.elements.iter() {
-                    if !exclude.contains(entry.key()) {
+                    if entry.value().is_live() && !exclude.contains(&entry.value().addr) {
                         members.push(entry)
                     }
                 }
+                if members.is_empty() {
+                    break;
+                }
 
                 // Pick random indices linearly (slow)
                 let mut rng = rand::thread_rng();
                 let mut indices: HashSet<usize> = HashSet::new();
-                while indices.len() < self.elements.len() {
-                    let r: usize = rng.gen_range(0, self.elements.len());
+                while indices.len() < members.len() {
+                    let r: usize = rng.gen_range(0, members.len());
                     indices.insert(r);
                 }
 
                 for i in indices.iter().cloned() {
-                    let addr = members[i].key();
+                    let id = members[i].key();
                     self.proto_ordering_worker.lock().unwrap()
-                        .push(addr.clone());
+                        .push(id.clone());
                 }
             }
         }
         addrs
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use futures::{Future, Stream};
+
+    fn addr(s: &str) -> NetAddr {
+        let socket_addr: SocketAddr = s.parse().unwrap();
+        NetAddr::new(socket_addr)
+    }
+
+    #[test]
+    fn test_suspect_overrides_alive_at_equal_incarnation() {
+        let membership = Membership::new();
+        let peer = NodeId::new("peer");
+        assert!(membership.process_join(peer.clone(), addr("127.0.0.1:1234"), 0, Tags::new()));
+        assert!(membership.suspect(peer.clone(), 0));
+        assert!(!membership.alive(peer.clone(), addr("127.0.0.1:1234"), 0));
+        assert_eq!(membership.get(&peer).unwrap().value().state, State::Suspected);
+    }
+
+    #[test]
+    fn test_higher_incarnation_wins() {
+        let membership = Membership::new();
+        let peer = NodeId::new("peer");
+        assert!(membership.process_join(peer.clone(), addr("127.0.0.1:1234"), 0, Tags::new()));
+        assert!(membership.alive(peer.clone(), addr("127.0.0.1:1235"), 2));
+        assert!(!membership.suspect(peer.clone(), 1));
+        assert_eq!(membership.get(&peer).unwrap().value().state, State::Alive);
+        assert_eq!(membership.incarnation(&peer), Some(2));
+        assert_eq!(membership.addr(&peer), Some(addr("127.0.0.1:1235")));
+    }
+
+    #[test]
+    fn test_confirm_overrides_everything() {
+        let membership = Membership::new();
+        let peer = NodeId::new("peer");
+        assert!(membership.process_join(peer.clone(), addr("127.0.0.1:1234"), 5, Tags::new()));
+        assert!(membership.confirm(&peer, 4));
+        assert_eq!(membership.get(&peer).unwrap().value().state, State::Dead);
+        assert_eq!(membership.len(), 0);
+        assert!(!membership.confirm(&peer, 5));
+    }
+
+    #[test]
+    fn test_tombstone_rejoin() {
+        let membership = Membership::new();
+        let peer = NodeId::new("peer");
+        assert!(membership.process_join(peer.clone(), addr("127.0.0.1:1234"), 5, Tags::new()));
+        assert!(membership.confirm(&peer, 5));
+        // stale gossip cannot resurrect the member
+        assert!(!membership.process_join(peer.clone(), addr("127.0.0.1:1234"), 5, Tags::new()));
+        assert!(!membership.alive(peer.clone(), addr("127.0.0.1:1234"), 5));
+        // a restart with a newer incarnation can
+        assert!(membership.process_join(peer.clone(), addr("127.0.0.1:1234"), 6, Tags::new()));
+        assert_eq!(membership.len(), 1);
+        membership.reap(Duration::from_millis(0));
+        assert_eq!(membership.len(), 1);
+    }
+
+    #[test]
+    fn test_update_tags() {
+        let membership = Membership::new();
+        let peer = NodeId::new("peer");
+        let mut tags = Tags::new();
+        tags.insert("role".to_string(), "db".to_string());
+        assert!(membership.process_join(peer.clone(), addr("127.0.0.1:1234"), 0, tags.clone()));
+        assert_eq!(membership.with_tag("role", "db"), vec![peer.clone()]);
+        tags.insert("role".to_string(), "web".to_string());
+        assert!(!membership.update(peer.clone(), 0, membership.tags(&peer).unwrap()));
+        assert!(membership.update(peer.clone(), 1, tags.clone()));
+        assert!(membership.with_tag("role", "db").is_empty());
+        assert_eq!(membership.with_tag("role", "web"), vec![peer.clone()]);
+        assert!(membership.confirm(&peer, 1));
+        assert!(!membership.update(peer.clone(), 2, tags));
+        assert!(membership.with_tag("role", "web").is_empty());
+    }
+
+    #[test]
+    fn test_update_keeps_suspicion() {
+        let membership = Membership::new();
+        let peer = NodeId::new("peer");
+        let mut tags = Tags::new();
+        assert!(membership.process_join(peer.clone(), addr("127.0.0.1:1234"), 0, tags.clone()));
+        assert!(membership.suspect(peer.clone(), 0));
+        tags.insert("role".to_string(), "web".to_string());
+        assert!(membership.update(peer.clone(), 0, tags.clone()));
+        assert!(membership.is_suspected(&peer, 0));
+        assert_eq!(membership.tags(&peer), Some(tags.clone()));
+        assert!(membership.update(peer.clone(), 1, Tags::new()));
+        assert!(!membership.is_suspected(&peer, 1));
+    }
+
+    #[test]
+    fn test_member_events() {
+        let membership = Membership::new();
+        let events = membership.subscribe();
+        let peer = NodeId::new("peer");
+        assert!(membership.process_join(peer.clone(), addr("127.0.0.1:1234"), 0, Tags::new()));
+        assert!(membership.suspect(peer.clone(), 0));
+        assert!(membership.alive(peer.clone(), addr("127.0.0.1:1234"), 1));
+        assert!(membership.confirm(&peer, 1));
+        membership.reap(Duration::from_millis(0));
+        let events: Vec<MemberEvent> = events.take(5).collect().wait().unwrap();
+        match events.as_slice() {
+            [MemberEvent::Joined(_), MemberEvent::Suspected(_), MemberEvent::Recovered(_),
+             MemberEvent::Failed(_), MemberEvent::Reaped(_)] => (),
+            _ => panic!("unexpected events {:?}", events),
+        }
+    }
+}
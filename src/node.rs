@@ -0,0 +1,222 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use tokio::prelude::*;
+use tokio::runtime::{Runtime, TaskExecutor};
+use config::Config;
+use discovery::{Resolver, Seed};
+use server::Server;
+use swim::Swim;
+use protocol::snowball::Snowball;
+use types::{NodeId, Tags};
+
+/// Configures a node before starting it in-process.
+
+pub struct NodeBuilder {
+    addr: SocketAddr,
+    id: Option<NodeId>,
+    tags: Tags,
+    seeds: Vec<String>,
+    resolver: Option<Arc<dyn Resolver>>,
+    config: Config,
+    delay: Option<u64>,
+}
+
+impl NodeBuilder {
+
+    pub fn new(addr: SocketAddr) -> NodeBuilder {
+        NodeBuilder {
+            addr,
+            id: None,
+            tags: Tags::new(),
+            seeds: vec![],
+            resolver: None,
+            config: Config::default(),
+            delay: None,
+        }
+    }
+
+    /// The unique name of the node, a random UUID by default.
+    pub fn id(mut self, id: NodeId) -> NodeBuilder {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn tag(mut self, key: &str, value: &str) -> NodeBuilder {
+        self.tags.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn tags(mut self, tags: Tags) -> NodeBuilder {
+        self.tags = tags;
+        self
+    }
+
+    /// A member to bootstrap to in addition to the seeds configured, given
+    /// as an address, a host name and port or an SRV name prefixed by `srv:`.
+    /// Without any seed the node starts a cluster of its own.
+    pub fn seed<S: ToString>(mut self, seed: S) -> NodeBuilder {
+        self.seeds.push(seed.to_string());
+        self
+    }
+
+    /// Resolves seeds given by name through another resolver than the
+    /// system one, such as a `StaticResolver` in tests.
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> NodeBuilder {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// The protocol parameters, the LAN preset by default.
+    pub fn config(mut self, config: Config) -> NodeBuilder {
+        self.config = config;
+        self
+    }
+
+    /// An artificial delay introduced for testing.
+    pub fn delay(mut self, delay: u64) -> NodeBuilder {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Starts the node on a runtime of its own.
+    pub fn start(self) -> io::Result<Node> {
+        let runtime = Runtime::new()?;
+        let mut node = self.start_on(runtime.executor())?;
+        node.runtime = Some(runtime);
+        Ok(node)
+    }
+
+    /// Starts serving requests, joining the seed if any and running the
+    /// protocol period, as tasks on an executor supplied by the application.
+    pub fn start_on(self, executor: TaskExecutor) -> io::Result<Node> {
+        let id = self.id.unwrap_or_else(NodeId::random);
+        info!("starting node {:?}", id);
+
+        let mut config = self.config;
+        config.seeds.extend(self.seeds);
+        for seed in &config.seeds {
+            let _: Seed = seed.parse()?;
+        }
+        let mut swim = Swim::new(id, self.addr, self.tags, config, self.delay)?;
+        if let Some(resolver) = self.resolver {
+            swim = swim.with_resolver(resolver);
+        }
+        let snowball = Arc::new(Mutex::new(Snowball::new(swim.addr.clone(), swim.config(), swim.connector().clone())));
+        let server = Server::new(self.addr, swim.clone(), snowball.clone());
+
+        let (server_shutdown_tx, server_shutdown_rx) = oneshot::channel::<()>();
+        let (server_done_tx, server_done_rx) = oneshot::channel::<()>();
+        let serve = server.clone().serve(server_shutdown_rx.map_err(|_| ()))?
+            .then(move |_| -> Result<(), ()> {
+                let _ = server_done_tx.send(());
+                Ok(())
+            });
+        executor.spawn(serve);
+
+        // the protocol period runs while the seeds are being joined, the
+        // bootstrap is abandoned if the node stops first
+        let bootstrap = swim.bootstrap();
+        let (protocol_shutdown_tx, protocol_shutdown_rx) = oneshot::channel::<bool>();
+        let (protocol_done_tx, protocol_done_rx) = oneshot::channel::<()>();
+        let swim_1 = swim.clone();
+        // a dropped node stops without leaving
+        let shutdown = protocol_shutdown_rx
+            .map_err(|_| ())
+            .and_then(move |leave| {
+                if leave {
+                    Either::A(swim_1.leave())
+                } else {
+                    Either::B(future::ok(()))
+                }
+            });
+        let protocol = swim.clone().run(snowball.clone(), shutdown)
+            .select(bootstrap.then(|_| future::empty()))
+            .then(move |_| -> Result<(), ()> {
+                let _ = protocol_done_tx.send(());
+                Ok(())
+            });
+        executor.spawn(protocol);
+
+        Ok(Node {
+            swim,
+            server,
+            snowball,
+            runtime: None,
+            protocol_shutdown_tx: Some(protocol_shutdown_tx),
+            protocol_done_rx: Some(protocol_done_rx),
+            server_shutdown_tx: Some(server_shutdown_tx),
+            server_done_rx: Some(server_done_rx),
+        })
+    }
+}
+
+/// A running node, stopped by leaving the cluster or simply dropping it.
+/// Stopping blocks until the node's tasks have completed, so it must not be
+/// done from a task on the runtime the node runs on.
+
+pub struct Node {
+    swim: Swim,
+    server: Server,
+    snowball: Arc<Mutex<Snowball>>,
+    runtime: Option<Runtime>,
+    protocol_shutdown_tx: Option<oneshot::Sender<bool>>,
+    protocol_done_rx: Option<oneshot::Receiver<()>>,
+    server_shutdown_tx: Option<oneshot::Sender<()>>,
+    server_done_rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Node {
+
+    pub fn swim(&self) -> &Swim {
+        &self.swim
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    pub fn snowball(&self) -> Arc<Mutex<Snowball>> {
+        self.snowball.clone()
+    }
+
+    /// Announces that this node is leaving before stopping it.
+    pub fn leave(mut self) {
+        self.shutdown(true);
+    }
+
+    /// Stops the node without announcing it, so members will detect it as
+    /// failed.
+    pub fn stop(mut self) {
+        self.shutdown(false);
+    }
+
+    // The protocol period stops first, so that the node keeps answering
+    // probes while its leave is disseminated
+    fn shutdown(&mut self, leave: bool) {
+        if let Some(shutdown_tx) = self.protocol_shutdown_tx.take() {
+            let _ = shutdown_tx.send(leave);
+        }
+        if let Some(done_rx) = self.protocol_done_rx.take() {
+            let _ = done_rx.wait();
+        }
+        if let Some(shutdown_tx) = self.server_shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+        if let Some(done_rx) = self.server_done_rx.take() {
+            let _ = done_rx.wait();
+        }
+        // an owned runtime drains the requests still in flight
+        if let Some(runtime) = self.runtime.take() {
+            let _ = runtime.shutdown_on_idle().wait();
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.shutdown(false);
+    }
+}
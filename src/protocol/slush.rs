@@ -1,3 +1,4 @@
+use std::sync::Arc;
 use std::time::Duration;
 use rand::{self, Rng};
 use tokio::prelude::*;
@@ -6,23 +7,20 @@ use colored::Colorize;
 use ::types::{NetAddr, Response, Request, ResponseTx};
 use protocol::types::{Colour, ColourTx, ColourRx};
 use membership::Membership;
-use client;
-use constants::ROUND_TRIP_TIME;
-
-// The protocol alpha parameter
-const A: f32 = 0.5;
-// The number of peers probed
-const K: usize = 4;
+use client::Connector;
+use config::Config;
 
 #[derive(Clone)]
 pub struct Slush {
     pub addr: NetAddr,
+    config: Arc<Config>,
+    connector: Connector,
     pub col: Colour,
 }
 
 impl Slush {
 
-    pub fn new(addr: NetAddr) -> Slush {
+    pub fn new(addr: NetAddr, config: Arc<Config>, connector: Connector) -> Slush {
         let mut rng = rand::thread_rng();
         let r: usize = rng.gen_range(0, 3);
         let col = match r {
@@ -40,7 +38,7 @@ impl Slush {
             },
             _ => panic!("[slush] not possible"),
         };
-        Slush { addr, col }
+        Slush { addr, config, connector, col }
     }
 
     pub fn set_col(&mut self, col: Colour) {
@@ -48,9 +46,9 @@ impl Slush {
     }
 
     pub fn send_query(&self, tx: ColourTx, peer_addr: NetAddr) {
-        let timeout = Duration::from_millis(ROUND_TRIP_TIME);
+        let timeout = Duration::from_millis(self.config.round_trip_time);
         let message = Request::Query(self.addr.clone(), self.col.clone());
-        let send = client::request(peer_addr.to_socket_addr().clone(), message, timeout)
+        let send = self.connector.request(peer_addr.to_socket_addr().clone(), message, timeout)
             .and_then(move |message| {
                 if let Response::Respond(col) = message {
                     let _ = tx.unbounded_send(col).unwrap();
@@ -73,11 +71,11 @@ impl Slush {
     }
 
     pub fn run(&mut self, tx: &ColourTx, rx: &mut ColourRx, membership: &Membership) {
-        if membership.len() >= K {
+        if membership.len() >= self.config.consensus.k {
             if self.col != Colour::Undecided {
                 
                 // randomised round robin sampling from known nodes
-                let members = membership.sample(K, vec![self.addr.clone()]);
+                let members = membership.sample(self.config.consensus.k, vec![self.addr.clone()]);
                 for peer_addr in members {
                     self.send_query(tx.clone(), peer_addr.clone());
                 }
@@ -87,13 +85,13 @@ impl Slush {
                 while let Ok(Async::Ready(Some(col))) = rx.poll() {
                     v.push(col);
                     i += 1;
-                    if i >= K {
+                    if i >= self.config.consensus.k {
                         break;
                     }
                 }
 
                 let (red, blue) = outcome(v);
-                let quiescent_point = (A * (K as f32)).round() as u32;
+                let quiescent_point = (self.config.consensus.alpha * (self.config.consensus.k as f32)).round() as u32;
 
                 if red > quiescent_point {
                     println!("{:?} {}", (red, blue), "converged to red".red());
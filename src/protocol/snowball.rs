@@ -1,3 +1,4 @@
+use std::sync::Arc;
 use std::time::Duration;
 use std::collections::HashMap;
 use rand::{self, Rng};
@@ -7,15 +8,13 @@ use colored::Colorize;
 use ::types::{NetAddr, Response, Request, ResponseTx};
 use protocol::types::{Colour, ColourTx, ColourRx};
 use membership::Membership;
-use client;
-use constants::ROUND_TRIP_TIME;
-
-const A: f32 = 0.5;
-const B: u32 = 11;
-const K: usize = 4;
+use client::Connector;
+use config::Config;
 
 pub struct Snowball {
     pub addr: NetAddr,
+    config: Arc<Config>,
+    connector: Connector,
     pub col: Colour,
     pub lastcol: Colour,
     pub cnt: u32,
@@ -24,7 +23,7 @@ pub struct Snowball {
 
 impl Snowball {
 
-    pub fn new(addr: NetAddr) -> Snowball {
+    pub fn new(addr: NetAddr, config: Arc<Config>, connector: Connector) -> Snowball {
         let mut rng = rand::thread_rng();
         let r: usize = rng.gen_range(0, 3);
         let col = match r {
@@ -48,7 +47,7 @@ impl Snowball {
         let mut d = HashMap::new();
         let _ = d.insert(Colour::Red, 0u32);
         let _ = d.insert(Colour::Blue, 0u32);
-        Snowball { addr, col: col.clone(), lastcol: col, cnt: 0u32, d }
+        Snowball { addr, config, connector, col: col.clone(), lastcol: col, cnt: 0u32, d }
     }
 
     pub fn set_col(&mut self, col: Colour) {
@@ -64,9 +63,9 @@ impl Snowball {
     }
 
     pub fn send_query(&self, tx: ColourTx, peer_addr: NetAddr) {
-        let timeout = Duration::from_millis(ROUND_TRIP_TIME);
+        let timeout = Duration::from_millis(self.config.round_trip_time);
         let message = Request::Query(self.addr.clone(), self.col.clone());
-        let send = client::request(peer_addr.to_socket_addr().clone(), message, timeout)
+        let send = self.connector.request(peer_addr.to_socket_addr().clone(), message, timeout)
             .and_then(move |message| {
                 if let Response::Respond(col) = message {
                     let _ = tx.unbounded_send(col).unwrap();
@@ -89,10 +88,10 @@ impl Snowball {
     }
 
     pub fn run(&mut self, tx: &ColourTx, rx: &mut ColourRx, membership: &Membership) -> bool {
-        if membership.len() >= K {
+        if membership.len() >= self.config.consensus.k {
             if self.col != Colour::Undecided {
                 // randomised round robin sampling from known nodes
-                let members = membership.sample(K, vec![self.addr.clone()]);
+                let members = membership.sample(self.config.consensus.k, vec![self.addr.clone()]);
                 for peer_addr in members {
                     self.send_query(tx.clone(), peer_addr.clone());
                 }
@@ -102,13 +101,13 @@ impl Snowball {
                 while let Ok(Async::Ready(Some(col))) = rx.poll() {
                     v.push(col);
                     i += 1;
-                    if i >= K {
+                    if i >= self.config.consensus.k {
                         break;
                     }
                 }
 
                 let (red, blue) = outcome(v);
-                let quiescent_point = (A * (K as f32)).round() as u32;
+                let quiescent_point = (self.config.consensus.alpha * (self.config.consensus.k as f32)).round() as u32;
 
                 if red > quiescent_point {
                     self.d.entry(Colour::Red).and_modify(|d| *d += 1);
@@ -120,7 +119,7 @@ impl Snowball {
                     if self.lastcol == Colour::Red {
                         let cnt = self.cnt.clone();
                         self.set_cnt(cnt + 1);
-                        if self.cnt > B {
+                        if self.cnt > self.config.consensus.beta {
                             info!("{}", "decided on red".red());
                             return true;
                         }
@@ -140,7 +139,7 @@ impl Snowball {
                     if self.lastcol == Colour::Blue {
                         let cnt = self.cnt.clone();
                         self.set_cnt(cnt + 1);
-                        if self.cnt > B {
+                        if self.cnt > self.config.consensus.beta {
                             info!("{}", "decided on blue".blue());
                             return true;
                         }
@@ -0,0 +1,113 @@
+use std::sync::{Arc, RwLock};
+use std::collections::HashMap;
+use membership::{MemberState, State};
+use types::{NodeId, NetAddr, Tags};
+
+/// Selects the members a query is sent to, every live member by default.
+
+#[derive(Debug, Clone)]
+pub struct QueryFilter {
+    tags: Tags,
+    addrs: Vec<NetAddr>,
+}
+
+impl QueryFilter {
+
+    pub fn all() -> QueryFilter {
+        QueryFilter { tags: Tags::new(), addrs: vec![] }
+    }
+
+    /// Only members carrying the given tag, every tag added has to match.
+    pub fn with_tag(mut self, key: &str, value: &str) -> QueryFilter {
+        self.tags.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Only members at one of the addresses added.
+    pub fn with_addr(mut self, addr: NetAddr) -> QueryFilter {
+        self.addrs.push(addr);
+        self
+    }
+
+    pub fn matches(&self, member: &MemberState) -> bool {
+        let live = match member.state {
+            State::Alive | State::Suspected => true,
+            State::Dead | State::Left => false,
+        };
+        let tagged = self.tags.iter()
+            .all(|(key, value)| member.tags.get(key) == Some(value));
+        let addressed = self.addrs.is_empty() || self.addrs.contains(&member.addr);
+        live && tagged && addressed
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryResponse {
+    pub from: NodeId,
+    pub payload: Vec<u8>,
+}
+
+/// Answers a named query given the member asking and the query payload.
+pub type QueryHandler = Box<dyn Fn(&NodeId, &[u8]) -> Vec<u8> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct QueryHandlers {
+    handlers: Arc<RwLock<HashMap<String, QueryHandler>>>,
+}
+
+impl QueryHandlers {
+
+    pub fn new() -> QueryHandlers {
+        QueryHandlers {
+            handlers: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Registers the handler for a query name, replacing any previous one.
+    pub fn register<F>(&self, name: &str, handler: F)
+        where
+          F: Fn(&NodeId, &[u8]) -> Vec<u8> + Send + Sync + 'static
+    {
+        self.handlers.write().unwrap().insert(name.to_string(), Box::new(handler));
+    }
+
+    pub fn handle(&self, name: &str, from: &NodeId, payload: &[u8]) -> Option<Vec<u8>> {
+        self.handlers.read().unwrap()
+            .get(name)
+            .map(|handler| handler(from, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn member(addr: &str, role: &str, state: State) -> MemberState {
+        let socket_addr: SocketAddr = addr.parse().unwrap();
+        let mut tags = Tags::new();
+        tags.insert("role".to_string(), role.to_string());
+        MemberState {
+            id: NodeId::new(addr),
+            addr: NetAddr::new(socket_addr),
+            state,
+            incarnation: 0,
+            tags,
+        }
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let db = member("127.0.0.1:1234", "db", State::Alive);
+        let web = member("127.0.0.1:1235", "web", State::Suspected);
+        let dead = member("127.0.0.1:1236", "db", State::Dead);
+        assert!(QueryFilter::all().matches(&web));
+        assert!(!QueryFilter::all().matches(&dead));
+        let filter = QueryFilter::all().with_tag("role", "db");
+        assert!(filter.matches(&db));
+        assert!(!filter.matches(&web));
+        let filter = QueryFilter::all().with_addr(web.addr.clone());
+        assert!(!filter.matches(&db));
+        assert!(filter.matches(&web));
+    }
+}
@@ -1,24 +1,24 @@
 use std::io;
 use std::net::SocketAddr;
-use std::thread;
 use std::sync::{Arc, Mutex};
 use futures::sync::mpsc;
 use futures::sync::mpsc::UnboundedSender;
 use tokio;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use tokio::prelude::*;
 use bincode_channel;
+use bincode_codec::Compression;
 use swim::Swim;
 use protocol::snowball::Snowball;
-use types::{Request, Response};
-
-// TODO Add TLS support
+use query::QueryHandlers;
+use types::{NodeId, Request, Response};
 
 #[derive(Clone)]
 pub struct Server {
     pub addr: SocketAddr,
     pub swim: Arc<Swim>,
     pub snowflake: Arc<Mutex<Snowball>>,
+    query_handlers: QueryHandlers,
 }
 
 impl Server {
@@ -27,36 +27,60 @@ impl Server {
             addr: addr.clone(),
             swim: Arc::new(swim),
             snowflake: snowflake,
+            query_handlers: QueryHandlers::new(),
         }
     }
 
-    pub fn bootstrap(&self, bootstrap_addr: SocketAddr) {
-        self.swim.send_bootstrap_join(bootstrap_addr);
+    /// Registers the handler answering user queries of the given name.
+    pub fn register_query_handler<F>(&self, name: &str, handler: F)
+        where
+          F: Fn(&NodeId, &[u8]) -> Vec<u8> + Send + Sync + 'static
+    {
+        self.query_handlers.register(name, handler);
     }
 
     fn process_input(self, sender: UnboundedSender<Response>, request: Request) {
         debug!("RECV={:?}", request.clone());
         match request.clone() {
             // SWIM
-            Request::Join(peer_addr) =>
-                self.swim.handle_join(sender, peer_addr),
-            Request::Ping(_peer_addr, gossip_vec) =>
-                self.swim.handle_ping(sender, gossip_vec),
-            Request::PingReq(_peer_addr, suspect_addr) =>
-                self.swim.handle_ping_req(sender, suspect_addr),
+            Request::Join(peer_id, peer_addr, incarnation, tags, versions) =>
+                self.swim.handle_join(sender, peer_id, peer_addr, incarnation, tags, versions),
+            Request::Ping(_peer_addr, target_id, gossip_vec) =>
+                self.swim.handle_ping(sender, target_id, gossip_vec),
+            Request::PingReq(_peer_addr, suspect_id, suspect_addr) =>
+                self.swim.handle_ping_req(sender, suspect_id, suspect_addr),
+            Request::Leave(peer_id, incarnation) =>
+                self.swim.handle_leave(sender, peer_id, incarnation),
+            Request::PushPull(peer_id, members) =>
+                self.swim.handle_push_pull(sender, peer_id, members),
             // Protocol
             Request::Query(_peer_addr, col) => {
                 let mut snowflake = self.snowflake.lock().unwrap();
                 snowflake.handle_query(sender, col);
             }
+            // Application
+            Request::UserQuery(peer_id, name, payload) => {
+                match self.query_handlers.handle(&name, &peer_id, &payload) {
+                    Some(payload) => {
+                        let message = Response::UserQuery(self.swim.id.clone(), payload);
+                        let _ = sender.unbounded_send(message);
+                    }
+                    None =>
+                        warn!("no handler registered for query {:?}", name),
+                }
+            }
         }
     }
 
-    fn handle_connection(self, socket: TcpStream) {
+    fn handle_connection<S>(self, socket: S)
+        where
+          S: AsyncRead + AsyncWrite + Send + 'static
+    {
         // Splits the socket stream into bincode reader / writers
         let (read_half, write_half) = socket.split();
-        let writer = bincode_channel::new_writer::<Response>(write_half);
-        let reader = bincode_channel::new_reader::<Request>(read_half);
+        let compression = Compression::new(&self.swim.config(), false);
+        let writer = bincode_channel::new_writer::<_, Response>(write_half, self.swim.keyring().clone(), compression.clone());
+        let reader = bincode_channel::new_reader::<_, Request>(read_half, self.swim.keyring().clone(), compression);
 
         // Creates sender and receiver channels in order to read and
         // write data from / to the socket
@@ -84,21 +108,43 @@ impl Server {
         tokio::spawn(output_writer);
     }
 
-    pub fn spawn(self) -> thread::JoinHandle<()> {
-        thread::spawn(move || {
-            let bind_addr = self.addr.clone();
-            let listener = TcpListener::bind(&bind_addr).unwrap();
-            let server = listener
-                .incoming()
-                .for_each(move |socket| {
-                    let () = self.clone().handle_connection(socket);
-                    Ok(())
-                }).map_err(|err| {
-                    error!("spawn => {:?}", err);
-                });
-            info!("listening at {:?}", bind_addr);
-            tokio::run(server)
-        })
+    /// Binds the listeners, returning a future serving requests until the
+    /// shutdown future resolves, after which the listeners are closed.
+    pub fn serve<F>(self, shutdown: F) -> io::Result<impl Future<Item = (), Error = ()>>
+        where
+          F: Future<Item = (), Error = ()> + Send + 'static
+    {
+        let bind_addr = self.addr.clone();
+        let listener = TcpListener::bind(&bind_addr)?;
+        let server_1 = self.clone();
+        let datagrams = self.swim.transport()
+            .listen(move |request, sender| {
+                server_1.clone().process_input(sender, request)
+            })?;
+        let server = listener
+            .incoming()
+            .for_each(move |socket| {
+                let server = self.clone();
+                match self.swim.tls() {
+                    // a failed handshake only drops its connection
+                    Some(tls) => {
+                        let accept = tls.accept(socket)
+                            .map(move |socket| server.handle_connection(socket))
+                            .map_err(|err| warn!("accept => {:?}", err));
+                        tokio::spawn(accept);
+                    }
+                    None =>
+                        server.handle_connection(socket),
+                }
+                Ok(())
+            }).map_err(|err| {
+                error!("serve => {:?}", err);
+            });
+        info!("listening at {:?}", bind_addr);
+        Ok(server.join(datagrams).map(|_| ()).select(shutdown).then(move |_| -> Result<(), ()> {
+            info!("stopped listening at {:?}", bind_addr);
+            Ok(())
+        }))
     }
 
 }
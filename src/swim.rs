@@ -1,63 +1,343 @@
+use std::cmp;
+use std::io;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::time::{Instant, Duration};
-use futures::sync::mpsc::{self, UnboundedSender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
+use futures::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use futures::future::{self, Either, Loop};
+use futures::stream;
+use rand::{self, Rng};
 use tokio::prelude::*;
-use tokio::timer::Interval;
+use tokio::timer::{self, Delay, Interval};
 use tokio;
 use cache::TimeoutCache;
-use client;
-use membership::Membership;
-use dissemination::Dissemination;
-use constants::{PROTOCOL_PERIOD, ROUND_TRIP_TIME};
+use client::Connector;
+use udp::Transport;
+use health::LocalHealth;
+use discovery::{Discovery, Resolver, SystemResolver};
+use keyring::Keyring;
+use tls::Tls;
+use event::{LamportClock, EventBuffer};
+use query::{QueryFilter, QueryResponse};
+use membership::{Membership, MemberState, MemberEvent, State};
+use dissemination::{self, Dissemination};
+use config::Config;
 use protocol::snowball::Snowball;
-use types::{NetAddr, Request, Response, Gossip};
+use types::{NodeId, NetAddr, Incarnation, Tags, UserEvent, Request, Response, Gossip, Versions, MAX_ID_LEN};
+
+// Starting from the wall clock means a restarted node has a newer incarnation
+// than the tombstone left behind by its previous run.
+fn initial_incarnation() -> Incarnation {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as Incarnation)
+        .unwrap_or(0)
+}
+
+// A join carries the tags along with everything an update does
+fn check_tags(config: &Config, id: &NodeId, addr: &NetAddr, tags: &Tags) -> io::Result<()> {
+    if !dissemination::fits_budget(config, &Gossip::Join(id.clone(), addr.clone(), 0, tags.clone())) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "tags too large to be gossiped"));
+    }
+    Ok(())
+}
+
+// The delay before the next round of join attempts, doubling up to the maximum
+fn backoff_ms(min: u64, max: u64, attempt: u32) -> u64 {
+    let factor = 1u64 << cmp::min(attempt, 32);
+    cmp::min(max, min.saturating_mul(factor))
+}
 
 #[derive(Clone)]
 pub struct Swim {
+    pub id: NodeId,
     pub addr: NetAddr,
+    config: Arc<Config>,
+    discovery: Discovery,
+    keyring: Keyring,
+    tls: Option<Tls>,
+    connector: Connector,
     delay: Option<u64>,
+    incarnation: Arc<AtomicUsize>,
+    tags: Arc<Mutex<Tags>>,
+    transport: Transport,
+    health: LocalHealth,
     membership: Arc<Membership>,
     dissemination: Arc<Dissemination>,
     timeout_cache: Arc<TimeoutCache>,
+    event_clock: LamportClock,
+    event_buffer: Arc<Mutex<EventBuffer>>,
+    subscribers: Arc<Mutex<Vec<UnboundedSender<UserEvent>>>>,
 }
 
 impl Swim {
 
-    pub fn new(addr: SocketAddr, delay: Option<u64>) -> Swim {
-        Swim {
+    /// Fails if the id is too long, the tags or events cannot be gossiped
+    /// within the budget, or the keys or certificates configured are
+    /// malformed.
+    pub fn new(id: NodeId, addr: SocketAddr, tags: Tags, config: Config, delay: Option<u64>) -> io::Result<Swim> {
+        if !id.is_valid() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("node id must be 1 to {} bytes", MAX_ID_LEN)));
+        }
+        config.validate()?;
+        check_tags(&config, &id, &NetAddr::new(addr), &tags)?;
+        let keyring = Keyring::from_hex(&config.keys)?;
+        // datagrams bypass TLS, so only a keyring keeps others from gossiping
+        if config.tls.is_some() && keyring.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "tls requires keys to authenticate datagrams"));
+        }
+        let tls = match config.tls {
+            Some(ref tls) => Some(Tls::load(tls)?),
+            None => None,
+        };
+        let config = Arc::new(config);
+        Ok(Swim {
+            id,
             addr: NetAddr::new(addr),
+            config: config.clone(),
+            discovery: Discovery::new(&config, Arc::new(SystemResolver)),
+            keyring: keyring.clone(),
+            tls: tls.clone(),
+            connector: Connector::new(config.clone(), keyring.clone(), tls),
             delay,
+            incarnation: Arc::new(AtomicUsize::new(initial_incarnation())),
+            tags: Arc::new(Mutex::new(tags)),
+            transport: Transport::new(addr, keyring),
+            health: LocalHealth::new(config.max_local_health),
             membership: Arc::new(Membership::new()),
-            dissemination: Arc::new(Dissemination::new()),
-            timeout_cache: Arc::new(TimeoutCache::new()),
+            dissemination: Arc::new(Dissemination::new(config.clone())),
+            timeout_cache: Arc::new(TimeoutCache::new(config.clone())),
+            event_clock: LamportClock::new(),
+            event_buffer: Arc::new(Mutex::new(EventBuffer::new(config.event_buffer_size))),
+            subscribers: Arc::new(Mutex::new(vec![])),
+        })
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// Resolves the seeds given by name through another resolver.
+    pub fn with_resolver(self, resolver: Arc<dyn Resolver>) -> Swim {
+        let discovery = self.discovery.clone().with_resolver(resolver);
+        Swim { discovery, ..self }
+    }
+
+    /// The keys sealing the traffic of this node, shared with its transports
+    /// so that keys rotated here apply to every connection.
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    /// Wraps the connections accepted when TLS is configured.
+    pub fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+
+    /// Opens connections to other members.
+    pub fn connector(&self) -> &Connector {
+        &self.connector
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.clone()
+    }
+
+    pub fn incarnation(&self) -> Incarnation {
+        self.incarnation.load(Ordering::SeqCst)
+    }
+
+    pub fn tags(&self) -> Tags {
+        self.tags.lock().unwrap().clone()
+    }
+
+    /// Replaces the tags of this node, disseminating them under a new
+    /// incarnation so that they supersede the ones peers hold. Fails if the
+    /// tags are too large to be gossiped.
+    pub fn set_tags(&self, tags: Tags) -> io::Result<()> {
+        check_tags(&self.config, &self.id, &self.addr, &tags)?;
+        let mut current = self.tags.lock().unwrap();
+        let incarnation = self.incarnation.fetch_add(1, Ordering::SeqCst) + 1;
+        info!("updating tags to {:?} with incarnation {:?}", tags, incarnation);
+        *current = tags.clone();
+        self.dissemination.gossip_update(self.id.clone(), incarnation, tags);
+        Ok(())
+    }
+
+    /// Broadcasts an application event to every member, including this one.
+    /// Events are piggybacked on probes, so the payload has to stay small.
+    pub fn broadcast(&self, name: &str, payload: Vec<u8>) -> io::Result<()> {
+        let mut event = UserEvent {
+            time: 0,
+            origin: self.id.clone(),
+            name: name.to_string(),
+            payload,
+        };
+        let size = event.name.len() + event.payload.len();
+        if size > self.config.max_event_size || !dissemination::fits_budget(&self.config, &Gossip::Event(event.clone())) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "user event too large"));
+        }
+        event.time = self.event_clock.increment();
+        debug!("broadcasting {:?}", event);
+        self.process_event(event);
+        Ok(())
+    }
+
+    /// Returns a stream of the changes to the state of other members.
+    pub fn subscribe_members(&self) -> UnboundedReceiver<MemberEvent> {
+        self.membership.subscribe()
+    }
+
+    /// Returns a stream of the user events delivered to this node.
+    pub fn subscribe(&self) -> UnboundedReceiver<UserEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Sends a named query to every member matching the filter, this one
+    /// included, yielding responses as they arrive until the deadline.
+    pub fn query(&self, name: &str, payload: Vec<u8>, filter: QueryFilter, deadline: Duration)
+                 -> impl Stream<Item = QueryResponse, Error = ()>
+    {
+        let requests: Vec<_> = self.snapshot().into_iter()
+            .filter(|member| filter.matches(member))
+            .map(|member| {
+                let message = Request::UserQuery(self.id.clone(), name.to_string(), payload.clone());
+                let peer_id = member.id;
+                self.connector.request(member.addr.to_socket_addr(), message, deadline)
+                    .then(move |result| -> Result<Option<QueryResponse>, ()> {
+                        match result {
+                            Ok(Response::UserQuery(from, payload)) =>
+                                Ok(Some(QueryResponse { from, payload })),
+                            Ok(message) => {
+                                warn!("query {:?} => {:?}", peer_id, message);
+                                Ok(None)
+                            }
+                            Err(err) => {
+                                debug!("query {:?} => {:?}", peer_id, err);
+                                Ok(None)
+                            }
+                        }
+                    })
+            }).collect();
+        stream::futures_unordered(requests)
+            .filter_map(|response| response)
+    }
+
+    // Delivers an event the first time it is seen and keeps gossiping it
+    fn process_event(&self, event: UserEvent) {
+        self.event_clock.witness(event.time);
+        let now = self.event_clock.time();
+        if self.event_buffer.lock().unwrap().insert(&event, now) {
+            // subscribers which dropped their receiver are forgotten
+            self.subscribers.lock().unwrap()
+                .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+            self.dissemination.gossip_event(event);
         }
     }
 
-    pub fn send_bootstrap_join(&self, seed_addr: SocketAddr) {
+    // A degraded node waits longer for acks rather than suspecting its peers
+    fn probe_timeout(&self) -> Duration {
+        self.health.scale(Duration::from_millis(self.config.round_trip_time))
+    }
+
+    /// Joins the cluster through the configured seeds, resolved anew and
+    /// tried in random order with an exponential backoff between rounds until
+    /// one of them answers.
+    pub fn bootstrap(&self) -> impl Future<Item = (), Error = ()> {
+        if self.discovery.is_empty() {
+            return Either::A(future::ok(()));
+        }
+        let self_1 = self.clone();
+        let min = self.config.join_backoff_min;
+        let max = self.config.join_backoff_max;
+        let bootstrap = future::loop_fn(0, move |attempt: u32| {
+            self_1.join_any_seed()
+                .then(move |joined| {
+                    match joined {
+                        Ok(()) =>
+                            Either::A(future::ok(Loop::Break(()))),
+                        Err(()) => {
+                            let backoff = Duration::from_millis(backoff_ms(min, max, attempt));
+                            warn!("failed to join any seed, retrying in {:?}", backoff);
+                            let retry = Delay::new(Instant::now() + backoff)
+                                .then(move |_| -> Result<Loop<(), u32>, ()> {
+                                    Ok(Loop::Continue(attempt + 1))
+                                });
+                            Either::B(retry)
+                        }
+                    }
+                })
+        });
+        Either::B(bootstrap)
+    }
+
+    // Joins the seeds again under a new incarnation, as the members may hold
+    // a tombstone of this node at its current one
+    fn rejoin(&self) -> impl Future<Item = (), Error = ()> {
+        let incarnation = self.incarnation.fetch_add(1, Ordering::SeqCst) + 1;
+        info!("no members known, joining the seeds again with incarnation {:?}", incarnation);
+        self.join_any_seed()
+    }
+
+    // Tries each seed in turn, synchronising the membership with the first
+    // one that accepts the join
+    fn join_any_seed(&self) -> impl Future<Item = (), Error = ()> {
+        let self_1 = self.clone();
+        let self_2 = self.clone();
+        let self_addr = self.addr.to_socket_addr();
+        self.discovery.resolve()
+            .and_then(move |mut seeds| {
+                seeds.retain(|seed_addr| *seed_addr != self_addr);
+                rand::thread_rng().shuffle(&mut seeds);
+                stream::iter_ok::<_, ()>(seeds)
+                    .and_then(move |seed_addr| {
+                        self_1.send_bootstrap_join(seed_addr)
+                            .then(move |joined| -> Result<Option<SocketAddr>, ()> {
+                                Ok(joined.ok().map(|()| seed_addr))
+                            })
+                    })
+                    .filter_map(|joined| joined)
+                    .into_future()
+                    .map_err(|_| ())
+                    .and_then(|(joined, _seeds)| joined.ok_or(()))
+            })
+            // learn about the members whose joins are no longer gossiped
+            .and_then(move |seed_addr| self_2.push_pull(seed_addr))
+    }
+
+    fn send_bootstrap_join(&self, seed_addr: SocketAddr) -> impl Future<Item = (), Error = ()> {
         let membership = self.membership.clone();
         let dissemination = self.dissemination.clone();
-        let timeout = Duration::from_millis(ROUND_TRIP_TIME);
-        let message = Request::Join(self.addr.clone());
-        let request = client::request(seed_addr, message, timeout)
-            .and_then(move |message| {
-                if let Response::Join(peer_addr) = message {
-                    if membership.process_join(peer_addr.clone()) {
-                        dissemination.gossip_join(peer_addr)
+        let timeout = self.probe_timeout();
+        let message = Request::Join(self.id.clone(), self.addr.clone(), self.incarnation(), self.tags(), Versions::supported());
+        self.connector.request(seed_addr, message, timeout)
+            .map_err(move |err| {
+                warn!("send_bootstrap_join {:?} => {:?}", seed_addr, err)
+            })
+            .and_then(move |message| -> Result<(), ()> {
+                match message {
+                    Response::Join(peer_id, peer_addr, incarnation, tags) => {
+                        if membership.process_join(peer_id.clone(), peer_addr.clone(), incarnation, tags.clone()) {
+                            dissemination.gossip_join(peer_id, peer_addr, incarnation, tags)
+                        }
+                        Ok(())
                     }
-                } // else error
-                Ok(())
+                    // the seed speaks no version in common with this node
+                    Response::Nack => {
+                        warn!("send_bootstrap_join {:?} => join refused", seed_addr);
+                        Err(())
+                    }
+                    _ => Ok(()),
+                }
             })
-            .map_err(|err| {
-                warn!("send_bootstrap_join => {:?}", err)
-            });
-        tokio::run(request);
     }
 
     pub fn request_self_join(&self, peer_addr: SocketAddr) {
-        let timeout = Duration::from_millis(ROUND_TRIP_TIME);
-        let message = Request::Join(self.addr.clone());
-        let request = client::request(peer_addr, message, timeout)
+        let timeout = self.probe_timeout();
+        let message = Request::Join(self.id.clone(), self.addr.clone(), self.incarnation(), self.tags(), Versions::supported());
+        let request = self.connector.request(peer_addr, message, timeout)
             .and_then(|_message| {
                 Ok(())
             })
@@ -68,186 +348,635 @@ impl Swim {
     }
 
     pub fn send_self_join(&self, sender: UnboundedSender<Response>) {
-        let message = Response::Join(self.addr.clone());
+        let message = Response::Join(self.id.clone(), self.addr.clone(), self.incarnation(), self.tags());
         let _ = sender.unbounded_send(message).unwrap();
     }
 
-    pub fn send_ping(self, peer_addr: NetAddr) {
+    pub fn send_ping(self, peer_id: NodeId) {
+        let peer_addr = match self.membership.addr(&peer_id) {
+            Some(peer_addr) => peer_addr,
+            None => return,
+        };
         let self_1 = self.clone();
-        let self_2 = self.clone();
-        let timeout = Duration::from_millis(ROUND_TRIP_TIME);
+        let timeout = self.probe_timeout();
         let gossip = self.dissemination.acquire_gossip(&self.membership);
-        let message = Request::Ping(self.addr.clone(), gossip);
-        let request = client::request(peer_addr.to_socket_addr().clone(), message, timeout)
-            .and_then(move |message| {
-                if let Response::Ack(gossip_vec) = message {
-                    for gossip in gossip_vec {
-                        self_1.process_gossip(gossip);
+        let message = Request::Ping(self.addr.clone(), peer_id.clone(), gossip);
+        let request = self.transport.request(peer_addr.to_socket_addr().clone(), message, timeout)
+            .then(move |result| -> Result<(), ()> {
+                match result {
+                    Ok(Response::Ack(gossip_vec)) => {
+                        self_1.health.improve();
+                        for gossip in gossip_vec {
+                            self_1.process_gossip(gossip);
+                        }
                     }
-                } // else error
+                    Ok(message) => {
+                        // another member answers at the address, initiate a probe
+                        warn!("send_ping {:?} => {:?}", peer_addr, message);
+                        self_1.send_ping_req(peer_id);
+                    }
+                    Err(err) => {
+                        // if a timeout occurs, initiate a probe
+                        warn!("send_ping {:?} => {:?}", peer_addr, err);
+                        self_1.health.degrade();
+                        self_1.send_ping_req(peer_id);
+                    }
+                }
                 Ok(())
-            })
-            .map_err(move |err| {
-                // if a timeout occurs, initiate a probe
-                warn!("send_ping => {:?}", err);
-                self_2.send_ping_req(peer_addr);
             });
         tokio::spawn(request);
     }
 
-    pub fn send_ping_req(self, suspect_addr: NetAddr) {
-        let members = self.membership.sample(1, vec![self.addr.clone()]);
-        if members.len() > 0 {
-            debug!("initiating probe");
+    pub fn send_ping_req(self, suspect_id: NodeId) {
+        let suspect_addr = match self.membership.addr(&suspect_id) {
+            Some(suspect_addr) => suspect_addr,
+            None => return,
+        };
+        let exclude = vec![self.id.clone(), suspect_id.clone()];
+        let helpers: Vec<NetAddr> = self.membership.sample_random(self.config.ping_req_count, exclude)
+            .iter()
+            .filter_map(|helper_id| self.membership.addr(helper_id))
+            .collect();
+        if helpers.len() > 0 {
+            debug!("initiating probe via {:?}", helpers);
             let self_1 = self.clone();
             let self_2 = self.clone();
-            let timeout = Duration::from_millis(ROUND_TRIP_TIME);            
-            let message = Request::PingReq(self.addr.clone(), suspect_addr.clone());
-            let request = client::request(suspect_addr.to_socket_addr().clone(), message, timeout)
-                .and_then(move |message| {
-                    // suspect replies indirectly via probe
-                    if let Response::Ack(gossip_vec) = message {
-                        for gossip in gossip_vec {
-                            self_1.process_gossip(gossip);
+            // helpers need a round trip of their own to reach the suspect
+            let timeout = self.probe_timeout() * 2;
+            let requests: Vec<_> = helpers.into_iter().map(|helper_addr| {
+                let health = self.health.clone();
+                let message = Request::PingReq(self.addr.clone(), suspect_id.clone(), suspect_addr.clone());
+                self.transport.request(helper_addr.to_socket_addr().clone(), message, timeout)
+                    .then(move |result| {
+                        match result {
+                            Ok(Response::Ack(gossip_vec)) =>
+                                Ok(gossip_vec),
+                            Ok(message) => {
+                                // the helper is responsive but failed to reach the suspect
+                                debug!("send_ping_req {:?} => {:?}", helper_addr, message);
+                                Err(())
+                            }
+                            Err(err) => {
+                                // a missed nack hints that this node is the slow one
+                                warn!("send_ping_req {:?} => {:?}", helper_addr, err);
+                                health.degrade();
+                                Err(())
+                            }
                         }
+                    })
+            }).collect();
+            // the first relayed ack cancels the suspicion
+            let request = future::select_ok(requests)
+                .and_then(move |(gossip_vec, _pending)| {
+                    // suspect replies indirectly via probe
+                    for gossip in gossip_vec {
+                        self_1.process_gossip(gossip);
                     }
                     Ok(())
                 })
-                .map_err(move |err| {
-                    // probe timeout, suspect
-                    warn!("send_ping_req => {:?}", err);
-                    self_2.timeout_cache.create_suspect_timeout(suspect_addr.clone());
+                .map_err(move |()| {
+                    // every probe failed, suspect
+                    self_2.suspect(suspect_id);
                 });
             tokio::spawn(request);
+        } else {
+            self.suspect(suspect_id);
         }
     }
 
-    pub fn handle_join(&self, sender: UnboundedSender<Response>, peer_addr: NetAddr) {
-        if self.membership.process_join(peer_addr.clone()) {
-            self.send_self_join(sender);
-            self.dissemination.gossip_join(peer_addr);
+    pub fn handle_join(&self, sender: UnboundedSender<Response>, peer_id: NodeId, peer_addr: NetAddr, incarnation: Incarnation, tags: Tags, versions: Versions) {
+        if !Versions::supported().is_compatible(&versions) {
+            warn!("refusing join from {:?} speaking versions {:?}", peer_id, versions);
+            let _ = sender.unbounded_send(Response::Nack);
+            return;
+        }
+        if self.membership.process_join(peer_id.clone(), peer_addr.clone(), incarnation, tags.clone()) {
+            self.dissemination.gossip_join(peer_id, peer_addr, incarnation, tags);
         } else {
-            warn!("received duplicate join request for {:?}", peer_addr);
+            debug!("received duplicate join request for {:?}", peer_id);
         }
+        // the joining node waits for this answer, even if it is already known
+        self.send_self_join(sender);
     }
 
-    pub fn handle_ping(&self, sender: UnboundedSender<Response>, gossip_vec: Vec<Gossip>) {
+    pub fn handle_ping(&self, sender: UnboundedSender<Response>, target_id: NodeId, gossip_vec: Vec<Gossip>) {
         for gossip in gossip_vec {
             self.process_gossip(gossip);
         }
+        // a ping meant for a member which was at this address before must
+        // not keep that member alive
+        if target_id != self.id {
+            warn!("received ping for {:?}", target_id);
+            let _ = sender.unbounded_send(Response::Nack);
+            return;
+        }
         let gossip = self.dissemination.acquire_gossip(&self.membership);
         let message = Response::Ack(gossip);
         let _ = sender.unbounded_send(message).unwrap();
     }
 
-    pub fn handle_ping_req(&self, sender: UnboundedSender<Response>, suspect_addr: NetAddr) {
+    pub fn handle_ping_req(&self, sender: UnboundedSender<Response>, suspect_id: NodeId, suspect_addr: NetAddr) {
         // if this node is a suspect, immediately ack
-        if self.addr == suspect_addr {
+        if self.id == suspect_id {
             // gossip alive state?
             let gossip = self.dissemination.acquire_gossip(&self.membership);
             let message = Response::Ack(gossip);
             let _ = sender.unbounded_send(message);
         } else {
-            debug!("probe suspect {:?}", suspect_addr);
+            debug!("probe suspect {:?} at {:?}", suspect_id, suspect_addr);
             // send ping to suspect
             let self_1 = self.clone();
-            let self_2 = self.clone();
-            let timeout = Duration::from_millis(ROUND_TRIP_TIME);
+            let timeout = self.probe_timeout();
             let gossip = self.dissemination.acquire_gossip(&self.membership);
-            let message = Request::Ping(self.addr.clone(), gossip);
-            let request = client::request(suspect_addr.to_socket_addr().clone(), message, timeout)
-                .and_then(move |message| {
-                    // if suspect Acks within RTT -> send ack to sender
-                    if let Response::Ack(gossip_vec) = message.clone() {
-                        for gossip in gossip_vec {
-                            self_1.process_gossip(gossip);
+            let message = Request::Ping(self.addr.clone(), suspect_id, gossip);
+            let request = self.transport.request(suspect_addr.to_socket_addr().clone(), message, timeout)
+                .then(move |result| -> Result<(), ()> {
+                    match result {
+                        // if suspect Acks within RTT -> send ack to sender
+                        Ok(Response::Ack(gossip_vec)) => {
+                            for gossip in gossip_vec.clone() {
+                                self_1.process_gossip(gossip);
+                            }
+                            let _ = sender.unbounded_send(Response::Ack(gossip_vec));
+                        }
+                        // the requester suspects the peer once every probe
+                        // fails, a nack tells it that this node is still
+                        // responsive
+                        Ok(message) => {
+                            warn!("handle_ping_req {:?} => {:?}", suspect_addr, message);
+                            let _ = sender.unbounded_send(Response::Nack);
+                        }
+                        Err(err) => {
+                            warn!("handle_ping_req {:?} => {:?}", suspect_addr, err);
+                            let _ = sender.unbounded_send(Response::Nack);
                         }
-                        let _ = sender.unbounded_send(message).unwrap();
-                    } // else error
+                    }
                     Ok(())
-                })
-                .map_err(move |err| {
-                    // else suspect ...
-                    warn!("handle_ping_req => {:?}", err);
-                    self_2.timeout_cache.create_suspect_timeout(suspect_addr);
                 });
             tokio::spawn(request);
         }
     }
 
+    pub fn handle_leave(&self, sender: UnboundedSender<Response>, peer_id: NodeId, incarnation: Incarnation) {
+        self.process_gossip(Gossip::Leave(peer_id, incarnation));
+        let _ = sender.unbounded_send(Response::Ack(vec![]));
+    }
+
+    /// Announces that this node is leaving, resolving once the leave has been
+    /// sent to a sample of members and piggybacked as often as the gossip rate
+    /// allows, or the leave timeout elapses.
+    pub fn leave(&self) -> impl Future<Item = (), Error = ()> {
+        let incarnation = self.incarnation();
+        let gossip = Gossip::Leave(self.id.clone(), incarnation);
+        info!("leaving with incarnation {:?}", incarnation);
+        self.dissemination.try_gossip(gossip.clone());
+
+        let timeout = self.probe_timeout();
+        let peers: Vec<NetAddr> = self.membership.sample_random(self.config.ping_req_count, vec![self.id.clone()])
+            .iter()
+            .filter_map(|peer_id| self.membership.addr(peer_id))
+            .collect();
+        let requests: Vec<_> = peers.into_iter().map(|peer_addr| {
+            let message = Request::Leave(self.id.clone(), incarnation);
+            self.connector.request(peer_addr.to_socket_addr().clone(), message, timeout)
+                .then(move |result| -> Result<(), ()> {
+                    if let Err(err) = result {
+                        warn!("leave {:?} => {:?}", peer_addr, err);
+                    }
+                    Ok(())
+                })
+        }).collect();
+
+        let dissemination = self.dissemination.clone();
+        let membership = self.membership.clone();
+        let disseminated = Interval::new(Instant::now(), Duration::from_millis(self.config.round_trip_time))
+            .take_while(move |_instant| {
+                Ok(!dissemination.is_disseminated(&gossip, &membership))
+            })
+            .for_each(|_instant| Ok(()))
+            .timeout(Duration::from_millis(self.config.leave_timeout))
+            .then(|_| -> Result<(), ()> { Ok(()) });
+
+        future::join_all(requests).then(move |_| disseminated)
+    }
+
+    pub fn handle_push_pull(&self, sender: UnboundedSender<Response>, peer_id: NodeId, members: Vec<MemberState>) {
+        let message = Response::PushPull(self.id.clone(), self.snapshot());
+        let _ = sender.unbounded_send(message);
+        self.merge_state(peer_id, members);
+    }
+
+    /// Exchanges complete membership snapshots with a peer over TCP.
+    pub fn push_pull(&self, peer_addr: SocketAddr) -> impl Future<Item = (), Error = ()> {
+        let self_1 = self.clone();
+        let timeout = self.probe_timeout();
+        let message = Request::PushPull(self.id.clone(), self.snapshot());
+        self.connector.request(peer_addr, message, timeout)
+            .and_then(move |message| {
+                if let Response::PushPull(peer_id, members) = message {
+                    self_1.merge_state(peer_id, members);
+                } // else error
+                Ok(())
+            })
+            .map_err(move |err| {
+                warn!("push_pull {:?} => {:?}", peer_addr, err)
+            })
+    }
+
+    // This node is included so that the peer learns about it as well
+    fn snapshot(&self) -> Vec<MemberState> {
+        let mut members = self.membership.snapshot();
+        members.push(MemberState {
+            id: self.id.clone(),
+            addr: self.addr.clone(),
+            state: State::Alive,
+            incarnation: self.incarnation(),
+            tags: self.tags(),
+        });
+        members
+    }
+
+    // Merges a remote snapshot by applying each member as gossip, so that the
+    // usual incarnation precedence decides between conflicting states
+    fn merge_state(&self, peer_id: NodeId, members: Vec<MemberState>) {
+        debug!("merging {} members from {:?}", members.len(), peer_id);
+        for member in members {
+            match member.state {
+                State::Alive => {
+                    self.process_gossip(Gossip::Join(member.id.clone(), member.addr.clone(), member.incarnation, member.tags.clone()));
+                    self.process_gossip(Gossip::Alive(member.id.clone(), member.addr, member.incarnation));
+                    self.process_gossip(Gossip::Update(member.id, member.incarnation, member.tags));
+                }
+                // a snapshot does not tell who suspected the member, so
+                // it cannot count as an independent confirmation
+                State::Suspected => {
+                    self.process_gossip(Gossip::Suspect(member.id, member.incarnation, self.id.clone()));
+                }
+                State::Dead => {
+                    self.process_gossip(Gossip::Confirm(member.id, member.incarnation));
+                }
+                State::Left => {
+                    self.process_gossip(Gossip::Leave(member.id, member.incarnation));
+                }
+            }
+        }
+    }
+
     pub fn handle_timeouts(&self) {
         if let Ok(Async::Ready(timeouts)) = self.timeout_cache.poll_purge() {
-            for expired_addr in timeouts.suspect_addr_vec {
+            for expired_id in timeouts.suspect_id_vec {
                 // Disseminate confirmed failure
-                warn!("failure confirmed = {:?}", expired_addr.clone());
-                self.membership.remove(&expired_addr);
-                self.dissemination.gossip_confirm(expired_addr);
+                warn!("failure confirmed = {:?}", expired_id.clone());
+                if let Some(incarnation) = self.membership.incarnation(&expired_id) {
+                    if self.membership.confirm(&expired_id, incarnation) {
+                        self.dissemination.gossip_confirm(expired_id, incarnation);
+                    }
+                }
             }
         }
+        self.membership.reap(Duration::from_millis(self.config.tombstone_reap_time));
+    }
+
+    // Suspects a peer locally at its currently known incarnation
+    fn suspect(&self, peer_id: NodeId) {
+        if let Some(incarnation) = self.membership.incarnation(&peer_id) {
+            let from = self.id.clone();
+            self.process_suspicion(peer_id, incarnation, from);
+        }
+    }
+
+    // Starts a suspicion or, for an existing suspicion of the same incarnation,
+    // counts an independent confirmation which shortens its timeout
+    fn process_suspicion(&self, peer_id: NodeId, incarnation: Incarnation, from: NodeId) {
+        let cluster_size = self.membership.len() + 1;
+        let accepted = if self.membership.suspect(peer_id.clone(), incarnation) {
+            warn!("peer {:?} reported as suspected", peer_id.clone());
+            true
+        } else {
+            self.membership.is_suspected(&peer_id, incarnation)
+        };
+        if accepted && self.timeout_cache.create_suspect_timeout(peer_id.clone(), from.clone(), cluster_size) {
+            self.dissemination.gossip_suspect(peer_id, incarnation, from);
+        }
+    }
+
+    // Overrides a suspicion about this node by disseminating an alive message
+    // with a higher incarnation than the one suspected
+    fn refute(&self, incarnation: Incarnation) {
+        // the incarnation only ever grows, racing tag updates included
+        let refutation = incarnation + 1;
+        let advanced = self.incarnation.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+            if current < refutation { Some(refutation) } else { None }
+        });
+        if advanced.is_ok() {
+            // being suspected suggests this node is slow to respond
+            self.health.degrade();
+            warn!("refuting suspicion with incarnation {:?}", refutation);
+            self.dissemination.gossip_alive(self.id.clone(), self.addr.clone(), refutation);
+        }
     }
 
     fn process_gossip(&self, gossip: Gossip) {
         debug!("GOSSIP={:?}", gossip.clone());
         match gossip.clone() {
-            Gossip::Join(peer_addr) => {
-                if peer_addr.clone() != self.addr {
-                    if self.membership.process_join(peer_addr.clone()) {
+            Gossip::Join(peer_id, peer_addr, incarnation, tags) => {
+                if peer_id != self.id {
+                    if self.membership.process_join(peer_id.clone(), peer_addr.clone(), incarnation, tags.clone()) {
                         self.request_self_join(peer_addr.to_socket_addr().clone());
-                        self.dissemination.gossip_join(peer_addr);
+                        self.dissemination.gossip_join(peer_id, peer_addr, incarnation, tags);
                     }
                 }
             }
-            // Clear the suspect timeout & mark as alive
-            Gossip::Alive(peer_addr) => {
-                debug!("peer {:?} reported as alive", peer_addr.clone());
-                self.membership.alive(peer_addr.clone());
-                self.dissemination.gossip_alive(peer_addr.clone());
-                self.timeout_cache.remove_suspect_timeout(&peer_addr);
+            // Clear the suspect timeout & mark as alive, possibly at a new address
+            Gossip::Alive(peer_id, peer_addr, incarnation) => {
+                if self.membership.alive(peer_id.clone(), peer_addr.clone(), incarnation) {
+                    debug!("peer {:?} reported as alive at {:?}", peer_id.clone(), peer_addr.clone());
+                    self.dissemination.gossip_alive(peer_id.clone(), peer_addr, incarnation);
+                    self.timeout_cache.remove_suspect_timeout(&peer_id);
+                }
+            }
+            // Replace the tags of the peer, a newer incarnation also shows
+            // that it is alive
+            Gossip::Update(peer_id, incarnation, tags) => {
+                let newer = self.membership.incarnation(&peer_id)
+                    .map_or(false, |held| incarnation > held);
+                if peer_id != self.id && self.membership.update(peer_id.clone(), incarnation, tags.clone()) {
+                    self.dissemination.gossip_update(peer_id.clone(), incarnation, tags);
+                    if newer {
+                        self.timeout_cache.remove_suspect_timeout(&peer_id);
+                    }
+                }
+            }
+            // Refute any suspicion about this node
+            Gossip::Suspect(peer_id, incarnation, _) if peer_id == self.id => {
+                self.refute(incarnation);
             }
             // Create a suspect timeout & mark as suspected
-            Gossip::Suspect(peer_addr) => {
-                warn!("peer {:?} reported as suspected", peer_addr.clone());
-                self.membership.suspect(peer_addr.clone());
-                self.dissemination.gossip_suspect(peer_addr.clone());
-                self.timeout_cache.create_suspect_timeout(peer_addr);
+            Gossip::Suspect(peer_id, incarnation, from) => {
+                self.process_suspicion(peer_id, incarnation, from);
+            }
+            // Remove the peer without running the suspicion path
+            Gossip::Leave(peer_id, incarnation) => {
+                if peer_id != self.id && self.membership.leave(&peer_id, incarnation) {
+                    self.timeout_cache.remove_suspect_timeout(&peer_id);
+                    self.dissemination.gossip_leave(peer_id, incarnation);
+                }
+            }
+            // Deliver application events once
+            Gossip::Event(event) => {
+                self.process_event(event);
             }
             // Remove the peer from the membership map
-            Gossip::Confirm(peer_addr) => {
-                warn!("removing peer {:?} from membership map", peer_addr.clone());
-                self.membership.remove(&peer_addr);
-                self.dissemination.gossip_confirm(peer_addr);
+            Gossip::Confirm(peer_id, incarnation) => {
+                if self.membership.confirm(&peer_id, incarnation) {
+                    warn!("removing peer {:?} from membership map", peer_id.clone());
+                    self.dissemination.gossip_confirm(peer_id.clone(), incarnation);
+                    self.timeout_cache.remove_suspect_timeout(&peer_id);
+                }
             }
         }
     }
     
-    pub fn run(self, snowflake: Arc<Mutex<Snowball>>) {
+    /// Returns a future running the protocol period until the shutdown
+    /// future resolves.
+    pub fn run<F>(self, snowflake: Arc<Mutex<Snowball>>, shutdown: F) -> impl Future<Item = (), Error = ()>
+        where
+          F: Future<Item = (), Error = ()> + Send + 'static
+    {
+        let self_0 = self.clone();
         let mut decided = false;
+        let mut periods = 0;
         let (tx, mut rx) = mpsc::unbounded();
-        let protocol_period = Duration::from_millis(PROTOCOL_PERIOD);
+        let protocol_period = Duration::from_millis(self.config.protocol_period);
         let swim = Interval::new(Instant::now(), protocol_period)
             .for_each(move |_instant| {
                 debug!("membership.len() = {:?}", self.membership.len());
                 if self.membership.len() >= 2 {
-                    let addrs = self.membership.sample_rr(1, vec![self.addr.clone()]);
-                    if addrs.len() > 0 {
-                        let peer_addr = addrs[0].clone();
-                        self.clone().send_ping(peer_addr.clone());
+                    // a degraded node probes once every multiplier periods
+                    periods += 1;
+                    if periods >= self.health.multiplier() {
+                        periods = 0;
+                        let ids = self.membership.sample_rr(1, vec![self.id.clone()]);
+                        if ids.len() > 0 {
+                            let peer_id = ids[0].clone();
+                            self.clone().send_ping(peer_id);
+                        }
                     }
                     
                     if !decided {
                         decided = snowflake.lock().unwrap()
                             .run(&tx, &mut rx, &self.membership);
                     }
+                }
 
-                    self.handle_timeouts();
+                // suspicions expire and tombstones are reaped however few
+                // members remain
+                self.handle_timeouts();
 
-                    Ok(())
-                } else {
-                    Ok(())
+                Ok(())
+            }).map_err(|err| {
+                error!("interval error; err = {:?}", err);
+            });
+        // periodic anti-entropy with a random member
+        let self_1 = self_0.clone();
+        let push_pull_interval = Duration::from_millis(self_0.config.push_pull_interval);
+        let push_pull = Interval::new(Instant::now() + push_pull_interval, push_pull_interval)
+            .for_each(move |_instant| {
+                let ids = self_1.membership.sample_random(1, vec![self_1.id.clone()]);
+                if let Some(peer_addr) = ids.first().and_then(|peer_id| self_1.membership.addr(peer_id)) {
+                    tokio::spawn(self_1.push_pull(peer_addr.to_socket_addr().clone()));
                 }
+                Ok(())
             }).map_err(|err| {
                 error!("interval error; err = {:?}", err);
             });
-        tokio::run(swim);
+        // join the seeds again while no other member is known, or as soon as
+        // the seed file changes
+        let self_2 = self_0.clone();
+        let mut since_rejoin = Duration::from_millis(0);
+        let rejoin_interval = Duration::from_millis(self_0.config.rejoin_interval);
+        let rejoin = Interval::new(Instant::now() + protocol_period, protocol_period)
+            .for_each(move |_instant| {
+                since_rejoin += protocol_period;
+                if self_2.membership.len() > 0 || self_2.discovery.is_empty() {
+                    return Either::A(future::ok(()));
+                }
+                let due = since_rejoin >= rejoin_interval;
+                if due {
+                    since_rejoin = Duration::from_millis(0);
+                }
+                let self_3 = self_2.clone();
+                let rejoin = self_2.discovery.seed_file_changed()
+                    .then(move |changed| -> Result<(), timer::Error> {
+                        if due || changed.unwrap_or(false) {
+                            tokio::spawn(self_3.rejoin());
+                        }
+                        Ok(())
+                    });
+                Either::B(rejoin)
+            }).map_err(|err| {
+                error!("interval error; err = {:?}", err);
+            });
+        swim.join3(push_pull, rejoin).map(|_| ()).select(shutdown).then(|_| -> Result<(), ()> { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net;
+    use std::thread;
+    use bytes::BytesMut;
+    use tokio::codec::{Decoder, Encoder};
+    use tokio::runtime::Runtime;
+    use bincode_codec::BincodeCodec;
+    use types::{Datagram, SeqNo, PROTOCOL_VERSION};
+
+    fn new_swim(config: Config) -> Swim {
+        Swim::new(NodeId::new("self"), "127.0.0.1:0".parse().unwrap(), Tags::new(), config, None).unwrap()
+    }
+
+    fn join(swim: &Swim, name: &str, addr: SocketAddr) -> NodeId {
+        let peer_id = NodeId::new(name);
+        swim.membership.process_join(peer_id.clone(), NetAddr::new(addr), 1, Tags::new());
+        peer_id
+    }
+
+    #[test]
+    fn test_refute_suspicion() {
+        let swim = new_swim(Config::default());
+        let peer_id = join(&swim, "peer", "127.0.0.1:7001".parse().unwrap());
+        let incarnation = swim.incarnation();
+
+        swim.process_gossip(Gossip::Suspect(swim.id.clone(), incarnation, peer_id.clone()));
+        assert_eq!(swim.incarnation(), incarnation + 1);
+        let refutation = Gossip::Alive(swim.id.clone(), swim.addr.clone(), incarnation + 1);
+        assert!(!swim.dissemination.is_disseminated(&refutation, &swim.membership));
+
+        // a suspicion of an older incarnation is already refuted
+        swim.process_gossip(Gossip::Suspect(swim.id.clone(), incarnation, peer_id));
+        assert_eq!(swim.incarnation(), incarnation + 1);
+    }
+
+    // A helper answering the first ping-req it receives
+    fn helper(reply: fn(SeqNo) -> Datagram) -> SocketAddr {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; 65536];
+            let (len, peer_addr) = socket.recv_from(&mut buf).unwrap();
+            let mut codec = BincodeCodec::<Datagram>::new(Keyring::default());
+            if let Ok(Some(Some(Datagram::PingReq(seq, ..)))) = codec.decode(&mut BytesMut::from(&buf[..len])) {
+                let mut frame = BytesMut::new();
+                codec.encode(reply(seq), &mut frame).unwrap();
+                socket.send_to(&frame, peer_addr).unwrap();
+            }
+        });
+        addr
+    }
+
+    // Whether probing an unresponsive member through the helpers suspects it
+    fn probe(replies: Vec<fn(SeqNo) -> Datagram>) -> bool {
+        let mut config = Config::default();
+        config.ping_req_count = replies.len();
+        let swim = new_swim(config);
+        let silent = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let suspect_id = join(&swim, "suspect", silent.local_addr().unwrap());
+        for (i, reply) in replies.into_iter().enumerate() {
+            join(&swim, &format!("helper{}", i), helper(reply));
+        }
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(swim.transport().listen(|_, _| {}).unwrap());
+        let swim_1 = swim.clone();
+        let suspect_1 = suspect_id.clone();
+        runtime.block_on(future::lazy(move || {
+            swim_1.send_ping_req(suspect_1);
+            Delay::new(Instant::now() + Duration::from_millis(500))
+        })).unwrap();
+        swim.membership.is_suspected(&suspect_id, 1)
+    }
+
+    #[test]
+    fn test_suspect_once_every_helper_fails() {
+        let nack: fn(SeqNo) -> Datagram = |seq| Datagram::Nack(seq);
+        let ack: fn(SeqNo) -> Datagram = |seq| Datagram::Ack(seq, vec![]);
+        assert!(probe(vec![nack, nack]));
+        // a single relayed ack cancels the suspicion
+        assert!(!probe(vec![nack, ack]));
+    }
+
+    #[test]
+    fn test_leave_buries_suspect() {
+        let swim = new_swim(Config::default());
+        let peer_id = join(&swim, "peer", "127.0.0.1:7001".parse().unwrap());
+        let other_id = join(&swim, "other", "127.0.0.1:7002".parse().unwrap());
+        swim.process_gossip(Gossip::Suspect(peer_id.clone(), 1, other_id));
+
+        // an announcement predating the current incarnation is ignored
+        swim.process_gossip(Gossip::Leave(peer_id.clone(), 0));
+        assert!(swim.membership.is_live(&peer_id));
+
+        let (tx, rx) = mpsc::unbounded();
+        swim.handle_leave(tx, peer_id.clone(), 1);
+        assert!(!swim.membership.is_live(&peer_id));
+        assert!(!swim.dissemination.is_disseminated(&Gossip::Leave(peer_id, 1), &swim.membership));
+        match rx.wait().next() {
+            Some(Ok(Response::Ack(_))) => (),
+            _ => panic!("expected an ack"),
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        assert_eq!(backoff_ms(1000, 60000, 0), 1000);
+        assert_eq!(backoff_ms(1000, 60000, 1), 2000);
+        assert_eq!(backoff_ms(1000, 60000, 5), 32000);
+        assert_eq!(backoff_ms(1000, 60000, 6), 60000);
+        assert_eq!(backoff_ms(1000, 60000, 100), 60000);
+    }
+
+    #[test]
+    fn test_ping_for_another_member_is_nacked() {
+        let swim = new_swim(Config::default());
+        let (tx, rx) = mpsc::unbounded();
+        swim.handle_ping(tx.clone(), swim.id.clone(), vec![]);
+        swim.handle_ping(tx, NodeId::new("previous"), vec![]);
+        let responses: Vec<Response> = rx.collect().wait().unwrap();
+        match responses.as_slice() {
+            [Response::Ack(_), Response::Nack] => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_answer_every_compatible_join() {
+        let swim = new_swim(Config::default());
+        let (tx, rx) = mpsc::unbounded();
+        let peer_addr = NetAddr::new("127.0.0.1:7001".parse().unwrap());
+        for _ in 0..2 {
+            swim.handle_join(tx.clone(), NodeId::new("peer"), peer_addr.clone(), 1, Tags::new(), Versions::supported());
+        }
+        let versions = Versions { min: PROTOCOL_VERSION + 1, max: PROTOCOL_VERSION + 1 };
+        swim.handle_join(tx, NodeId::new("newer"), peer_addr, 1, Tags::new(), versions);
+        let responses: Vec<Response> = rx.collect().wait().unwrap();
+        match responses.as_slice() {
+            [Response::Join(..), Response::Join(..), Response::Nack] => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reject_gossip_beyond_budget() {
+        let swim = new_swim(Config::default());
+        let mut tags = Tags::new();
+        tags.insert("role".to_string(), "db".to_string());
+        swim.set_tags(tags.clone()).unwrap();
+        tags.insert("blob".to_string(), "x".repeat(swim.config.gossip_budget));
+        assert!(swim.set_tags(tags).is_err());
+        assert_eq!(swim.tags().len(), 1);
+
+        swim.broadcast("deploy", vec![0; swim.config.max_event_size - 6]).unwrap();
+        assert!(swim.broadcast("deploy", vec![0; swim.config.max_event_size]).is_err());
     }
 }
@@ -0,0 +1,164 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::prelude::*;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::webpki::DNSNameRef;
+use config::TlsConfig;
+
+/// Wraps TCP connections in TLS, both ends presenting a certificate issued
+/// by the cluster's certificate authority.
+
+#[derive(Clone)]
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    server_name: String,
+}
+
+impl Tls {
+
+    pub fn load(config: &TlsConfig) -> io::Result<Tls> {
+        if DNSNameRef::try_from_ascii_str(&config.server_name).is_err() {
+            return Err(invalid_input(format!("invalid server name {:?}", config.server_name)));
+        }
+        let roots = load_roots(&config.ca)?;
+        let certs = load_certs(&config.cert)?;
+        let key = load_key(&config.key)?;
+
+        let mut server_config = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots.clone()));
+        server_config.set_single_cert(certs.clone(), key.clone())
+            .map_err(|err| invalid_input(format!("invalid certificate => {:?}", err)))?;
+
+        let mut client_config = ClientConfig::new();
+        client_config.root_store = roots;
+        client_config.set_single_client_cert(certs, key);
+
+        Ok(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            connector: TlsConnector::from(Arc::new(client_config)),
+            server_name: config.server_name.clone(),
+        })
+    }
+
+    pub fn accept<S>(&self, stream: S) -> impl Future<Item = impl AsyncRead + AsyncWrite + Send, Error = io::Error>
+        where
+          S: AsyncRead + AsyncWrite + Send
+    {
+        self.acceptor.accept(stream)
+    }
+
+    // Every member presents a certificate for the same name, as members are
+    // addressed by IP
+    pub fn connect<S>(&self, stream: S) -> impl Future<Item = impl AsyncRead + AsyncWrite + Send, Error = io::Error>
+        where
+          S: AsyncRead + AsyncWrite + Send
+    {
+        let server_name = DNSNameRef::try_from_ascii_str(&self.server_name).unwrap();
+        self.connector.connect(server_name, stream)
+    }
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_pem_file(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| invalid_input(format!("invalid certificates in {:?}", path)))?;
+    if added == 0 {
+        return Err(invalid_input(format!("no certificates in {:?}", path)));
+    }
+    Ok(roots)
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| invalid_input(format!("invalid certificates in {:?}", path)))?;
+    if certs.is_empty() {
+        return Err(invalid_input(format!("no certificates in {:?}", path)));
+    }
+    Ok(certs)
+}
+
+// Accepts PKCS8 as well as RSA keys
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let invalid = || invalid_input(format!("invalid private key in {:?}", path));
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| invalid())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?))
+            .map_err(|_| invalid())?;
+    }
+    keys.into_iter().next().ok_or_else(|| invalid_input(format!("no private key in {:?}", path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tokio;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::runtime::Runtime;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/certs").join(name)
+    }
+
+    fn load(name: &str) -> Tls {
+        Tls::load(&TlsConfig {
+            ca: fixture("ca.pem"),
+            cert: fixture(&format!("{}.pem", name)),
+            key: fixture(&format!("{}.key", name)),
+            server_name: "swim.cluster".to_string(),
+        }).unwrap()
+    }
+
+    // Sends a message from the accepting end to the connecting end
+    fn handshake(server: Tls, client: Tls) -> io::Result<Vec<u8>> {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())?;
+        let addr = listener.local_addr()?;
+        let accept = listener.incoming().into_future()
+            .map_err(|(err, _)| err)
+            .and_then(move |(socket, _)| server.accept(socket.unwrap()))
+            .and_then(|stream| tokio::io::write_all(stream, b"ping"))
+            .and_then(|(stream, _)| tokio::io::flush(stream));
+        let connect = TcpStream::connect(&addr)
+            .and_then(move |socket| client.connect(socket))
+            .and_then(|stream| tokio::io::read_exact(stream, vec![0u8; 4]))
+            .map(|(_, message)| message);
+        let mut runtime = Runtime::new()?;
+        runtime.block_on(accept.join(connect)).map(|(_, message)| message)
+    }
+
+    #[test]
+    fn test_mutual_authentication() {
+        assert_eq!(handshake(load("node"), load("node")).unwrap(), b"ping".to_vec());
+
+        // a certificate issued by another authority is refused
+        assert!(handshake(load("node"), load("rogue")).is_err());
+
+        // as is a client presenting no certificate at all
+        let mut client_config = ClientConfig::new();
+        client_config.root_store = load_roots(&fixture("ca.pem")).unwrap();
+        let anonymous = Tls { connector: TlsConnector::from(Arc::new(client_config)), ..load("node") };
+        assert!(handshake(load("node"), anonymous).is_err());
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_config() {
+        let config = TlsConfig {
+            ca: PathBuf::from("/nonexistent/ca.pem"),
+            cert: PathBuf::from("/nonexistent/node.pem"),
+            key: PathBuf::from("/nonexistent/node.key"),
+            server_name: "swim.cluster".to_string(),
+        };
+        assert_eq!(Tls::load(&config).err().unwrap().kind(), io::ErrorKind::NotFound);
+        let config = TlsConfig { server_name: "not a name".to_string(), ..config };
+        assert_eq!(Tls::load(&config).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,7 +1,12 @@
 use std::net::SocketAddr;
+use std::collections::BTreeMap;
 use std::fmt;
 use futures::sync::mpsc::UnboundedSender;
+use rand::{self, Rng};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use protocol::types::Colour;
+use membership::MemberState;
 
 /// A wrapper type for SocketAddr with usable derivations.
 
@@ -26,57 +31,318 @@ impl fmt::Debug for NetAddr {
     }
 }
 
+/// The longest node id accepted, bounding the size of the pings naming it.
+pub const MAX_ID_LEN: usize = 128;
+
+/// A stable identity for a node, independent of the address it is reachable
+/// at, either chosen by name or generated randomly at startup.
+
+#[derive(Clone, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NodeId {
+    inner: String,
+}
+
+impl NodeId {
+    pub fn new(name: &str) -> NodeId {
+        NodeId { inner: name.to_string() }
+    }
+
+    /// Generates a random (version 4) UUID.
+    pub fn random() -> NodeId {
+        let mut rng = rand::thread_rng();
+        let mut bytes = [0u8; 16];
+        rng.fill(&mut bytes);
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let inner = format!("{}-{}-{}-{}-{}",
+                            hex[0..4].concat(), hex[4..6].concat(), hex[6..8].concat(),
+                            hex[8..10].concat(), hex[10..16].concat());
+        NodeId { inner }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.inner.is_empty() && self.inner.len() <= MAX_ID_LEN
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.inner)
+    }
+}
+
+/// A member's incarnation number, only ever incremented by the member itself
+/// in order to refute suspicion.
+
+pub type Incarnation = usize;
+
+/// Key/value metadata describing a node, such as its role or datacenter.
+
+pub type Tags = BTreeMap<String, String>;
+
+/// A logical timestamp ordering user events across the cluster.
+
+pub type LamportTime = usize;
+
+/// An application event broadcast to every member, identified by the member
+/// it originated from and the Lamport time it was sent at.
+
+#[derive(Clone, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserEvent {
+    pub time: LamportTime,
+    pub origin: NodeId,
+    pub name: String,
+    pub payload: Vec<u8>,
+}
+
+impl fmt::Debug for UserEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}({:?},{} bytes)", self.name, self.time, self.origin, self.payload.len())
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Gossip {
-    Join(NetAddr),
-    Alive(NetAddr),
-    Suspect(NetAddr),
-    Confirm(NetAddr),
+    // Alive states carry the address so that a member can move
+    Join(NodeId, NetAddr, Incarnation, Tags),
+    Alive(NodeId, NetAddr, Incarnation),
+    // A change of tags, versioned by the incarnation it was made at
+    Update(NodeId, Incarnation, Tags),
+    // The suspected member, its incarnation and the member suspecting it
+    Suspect(NodeId, Incarnation, NodeId),
+    Confirm(NodeId, Incarnation),
+    Leave(NodeId, Incarnation),
+    Event(UserEvent),
+}
+
+impl Gossip {
+    pub fn id(&self) -> &NodeId {
+        match self {
+            Gossip::Join(id, _, _, _) => id,
+            Gossip::Alive(id, _, _) => id,
+            Gossip::Update(id, _, _) => id,
+            Gossip::Suspect(id, _, _) => id,
+            Gossip::Confirm(id, _) => id,
+            Gossip::Leave(id, _) => id,
+            Gossip::Event(event) => &event.origin,
+        }
+    }
 }
 
 impl fmt::Debug for Gossip {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Gossip::Join(addr) =>
-                write!(f, "JOIN({:?})", addr),
-            Gossip::Alive(addr) =>
-                write!(f, "ALIVE({:?})", addr),
-            Gossip::Suspect(addr) =>
-                write!(f, "SUSPECT({:?})", addr),
-            Gossip::Confirm(addr) =>
-                write!(f, "CONFIRM({:?})", addr),
+            Gossip::Join(id, addr, incarnation, tags) =>
+                write!(f, "JOIN({:?},{:?},{:?},{:?})", id, addr, incarnation, tags),
+            Gossip::Alive(id, addr, incarnation) =>
+                write!(f, "ALIVE({:?},{:?},{:?})", id, addr, incarnation),
+            Gossip::Update(id, incarnation, tags) =>
+                write!(f, "UPDATE({:?},{:?},{:?})", id, incarnation, tags),
+            Gossip::Suspect(id, incarnation, from) =>
+                write!(f, "SUSPECT({:?},{:?},{:?})", id, incarnation, from),
+            Gossip::Confirm(id, incarnation) =>
+                write!(f, "CONFIRM({:?},{:?})", id, incarnation),
+            Gossip::Leave(id, incarnation) =>
+                write!(f, "LEAVE({:?},{:?})", id, incarnation),
+            Gossip::Event(event) =>
+                write!(f, "EVENT({:?})", event),
+        }
+    }
+}
+
+/// The version of the wire protocol spoken by this node, and the oldest one
+/// it still understands.
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// The range of protocol versions a member speaks, advertised when joining.
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Versions {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl Versions {
+    pub fn supported() -> Versions {
+        Versions { min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION }
+    }
+
+    pub fn is_compatible(&self, other: &Versions) -> bool {
+        self.min <= other.max && other.min <= self.max
+    }
+}
+
+/// A message framed in an envelope carrying its type, so that members can
+/// skip the types added by newer versions. New variants must therefore be
+/// added after the existing ones.
+
+pub trait Message: Serialize + DeserializeOwned {
+    fn kind(&self) -> u8;
+    // The number of types known to this version
+    fn kinds() -> u8;
+}
+
+/// The bytes framing each gossip item in a message, its type and length.
+pub const GOSSIP_HEADER_LEN: usize = 1 + 8;
+
+// Gossip is framed item by item, so that members skip the gossip types added
+// by newer versions rather than failing the whole message
+mod gossip_envelope {
+    use bincode;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::Error as DeError;
+    use serde::ser::Error as SerError;
+    use super::{Gossip, Message};
+
+    pub fn serialize<S: Serializer>(gossip_vec: &[Gossip], serializer: S) -> Result<S::Ok, S::Error> {
+        let items = gossip_vec.iter()
+            .map(|gossip| bincode::serialize(gossip).map(|bytes| (gossip.kind(), bytes)))
+            .collect::<Result<Vec<(u8, Vec<u8>)>, _>>()
+            .map_err(S::Error::custom)?;
+        items.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Gossip>, D::Error> {
+        let items: Vec<(u8, Vec<u8>)> = Deserialize::deserialize(deserializer)?;
+        let mut gossip_vec = vec![];
+        for (kind, bytes) in items {
+            if kind >= Gossip::kinds() {
+                debug!("skipping unknown gossip type {}", kind);
+                continue;
+            }
+            gossip_vec.push(bincode::deserialize(&bytes).map_err(D::Error::custom)?);
         }
+        Ok(gossip_vec)
     }
 }
 
+// Probes name the member they are meant for, as another member may have
+// taken over its address since.
+
 #[derive(Clone, Serialize, Deserialize)]
 pub enum Request {
-    Join(NetAddr),
-    Ping(NetAddr, Vec<Gossip>),
-    PingReq(NetAddr, NetAddr),
+    Join(NodeId, NetAddr, Incarnation, Tags, Versions),
+    Ping(NetAddr, NodeId, #[serde(with = "gossip_envelope")] Vec<Gossip>),
+    PingReq(NetAddr, NodeId, NetAddr),
+    Leave(NodeId, Incarnation),
+    PushPull(NodeId, Vec<MemberState>),
     Query(NetAddr, Colour),
+    // An application query by name, distinct from the consensus query
+    UserQuery(NodeId, String, Vec<u8>),
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub enum Response {
-    Join(NetAddr),
-    Ack(Vec<Gossip>),
+    Join(NodeId, NetAddr, Incarnation, Tags),
+    Ack(#[serde(with = "gossip_envelope")] Vec<Gossip>),
+    Nack,
+    PushPull(NodeId, Vec<MemberState>),
     Respond(Colour),
+    UserQuery(NodeId, Vec<u8>),
 }
 
 pub type ResponseTx = UnboundedSender<Response>;
 
+/// Sequence numbers match acks received over UDP to the probe they answer.
+
+pub type SeqNo = usize;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Datagram {
+    Ping(SeqNo, NetAddr, NodeId, #[serde(with = "gossip_envelope")] Vec<Gossip>),
+    PingReq(SeqNo, NetAddr, NodeId, NetAddr),
+    Ack(SeqNo, #[serde(with = "gossip_envelope")] Vec<Gossip>),
+    Nack(SeqNo),
+}
+
+impl Message for Request {
+    fn kind(&self) -> u8 {
+        match self {
+            Request::Join(..) => 0,
+            Request::Ping(..) => 1,
+            Request::PingReq(..) => 2,
+            Request::Leave(..) => 3,
+            Request::PushPull(..) => 4,
+            Request::Query(..) => 5,
+            Request::UserQuery(..) => 6,
+        }
+    }
+
+    fn kinds() -> u8 {
+        7
+    }
+}
+
+impl Message for Response {
+    fn kind(&self) -> u8 {
+        match self {
+            Response::Join(..) => 0,
+            Response::Ack(..) => 1,
+            Response::Nack => 2,
+            Response::PushPull(..) => 3,
+            Response::Respond(..) => 4,
+            Response::UserQuery(..) => 5,
+        }
+    }
+
+    fn kinds() -> u8 {
+        6
+    }
+}
+
+impl Message for Gossip {
+    fn kind(&self) -> u8 {
+        match self {
+            Gossip::Join(..) => 0,
+            Gossip::Alive(..) => 1,
+            Gossip::Update(..) => 2,
+            Gossip::Suspect(..) => 3,
+            Gossip::Confirm(..) => 4,
+            Gossip::Leave(..) => 5,
+            Gossip::Event(..) => 6,
+        }
+    }
+
+    fn kinds() -> u8 {
+        7
+    }
+}
+
+impl Message for Datagram {
+    fn kind(&self) -> u8 {
+        match self {
+            Datagram::Ping(..) => 0,
+            Datagram::PingReq(..) => 1,
+            Datagram::Ack(..) => 2,
+            Datagram::Nack(..) => 3,
+        }
+    }
+
+    fn kinds() -> u8 {
+        4
+    }
+}
+
 impl fmt::Debug for Request {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Request::Join(addr) =>
-                write!(f, "JOIN({:?})", addr),
-            Request::Ping(addr, gossip) =>
-                write!(f, "PING({:?},{:?})", addr, gossip),
-            Request::PingReq(peer_addr, suspect_addr) =>
-                write!(f, "PING-REQ({:?},{:?})", peer_addr, suspect_addr),
+            Request::Join(id, addr, incarnation, tags, versions) =>
+                write!(f, "JOIN({:?},{:?},{:?},{:?},v{}-{})", id, addr, incarnation, tags, versions.min, versions.max),
+            Request::Ping(addr, target_id, gossip) =>
+                write!(f, "PING({:?},{:?},{:?})", addr, target_id, gossip),
+            Request::PingReq(peer_addr, suspect_id, suspect_addr) =>
+                write!(f, "PING-REQ({:?},{:?},{:?})", peer_addr, suspect_id, suspect_addr),
+            Request::Leave(id, incarnation) =>
+                write!(f, "LEAVE({:?},{:?})", id, incarnation),
+            Request::PushPull(id, members) =>
+                write!(f, "PUSH-PULL({:?},{:?})", id, members),
             Request::Query(peer_addr, col) =>
                 write!(f, "QUERY({:?},{:?})", peer_addr, col),
+            Request::UserQuery(id, name, payload) =>
+                write!(f, "USER-QUERY({:?},{},{} bytes)", id, name, payload.len()),
         }
     }
 }
@@ -84,12 +350,33 @@ impl fmt::Debug for Request {
 impl fmt::Debug for Response {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Response::Join(addr) =>
-                write!(f, "JOIN({:?})", addr),
+            Response::Join(id, addr, incarnation, tags) =>
+                write!(f, "JOIN({:?},{:?},{:?},{:?})", id, addr, incarnation, tags),
             Response::Ack(gossip) =>
                 write!(f, "ACK({:?})", gossip),
+            Response::Nack =>
+                write!(f, "NACK"),
+            Response::PushPull(id, members) =>
+                write!(f, "PUSH-PULL({:?},{:?})", id, members),
             Response::Respond(col) =>
                 write!(f, "RESPOND({:?})", col),
+            Response::UserQuery(id, payload) =>
+                write!(f, "USER-QUERY({:?},{} bytes)", id, payload.len()),
+        }
+    }
+}
+
+impl fmt::Debug for Datagram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Datagram::Ping(seq, addr, target_id, gossip) =>
+                write!(f, "PING#{:?}({:?},{:?},{:?})", seq, addr, target_id, gossip),
+            Datagram::PingReq(seq, peer_addr, suspect_id, suspect_addr) =>
+                write!(f, "PING-REQ#{:?}({:?},{:?},{:?})", seq, peer_addr, suspect_id, suspect_addr),
+            Datagram::Ack(seq, gossip) =>
+                write!(f, "ACK#{:?}({:?})", seq, gossip),
+            Datagram::Nack(seq) =>
+                write!(f, "NACK#{:?}", seq),
         }
     }
 }
@@ -0,0 +1,219 @@
+use std::io;
+use std::net::{self, SocketAddr};
+use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use bytes::BytesMut;
+use futures::future;
+use futures::sync::{mpsc, oneshot};
+use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+use tokio::prelude::*;
+use tokio::codec::Encoder;
+use tokio::net::{UdpSocket, UdpFramed};
+use tokio::reactor::Handle;
+use tokio::timer::timeout;
+use tokio;
+use bincode_codec::BincodeCodec;
+use keyring::Keyring;
+use types::{Request, Response, ResponseTx, Datagram, SeqNo};
+
+type Outgoing = (Datagram, SocketAddr);
+
+/// A datagram transport for the probe traffic of the protocol period, joins
+/// and larger transfers remain on TCP.
+
+#[derive(Clone)]
+pub struct Transport {
+    addr: SocketAddr,
+    keyring: Keyring,
+    sequence: Arc<AtomicUsize>,
+    pending: Arc<Mutex<HashMap<SeqNo, oneshot::Sender<Response>>>>,
+    outgoing_tx: UnboundedSender<Outgoing>,
+    outgoing_rx: Arc<Mutex<Option<UnboundedReceiver<Outgoing>>>>,
+}
+
+impl Transport {
+
+    pub fn new(addr: SocketAddr, keyring: Keyring) -> Transport {
+        let (tx, rx) = mpsc::unbounded();
+        Transport {
+            addr,
+            keyring,
+            sequence: Arc::new(AtomicUsize::new(0)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            outgoing_tx: tx,
+            outgoing_rx: Arc::new(Mutex::new(Some(rx))),
+        }
+    }
+
+    pub fn request(&self, peer_addr: SocketAddr, req: Request, timeout: Duration) -> impl Future<Item = Response, Error = timeout::Error<io::Error>> {
+        let seq = self.sequence.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        let datagram = match req {
+            Request::Ping(addr, target_id, gossip) =>
+                Some(Datagram::Ping(seq, addr, target_id, gossip)),
+            Request::PingReq(addr, suspect_id, suspect_addr) =>
+                Some(Datagram::PingReq(seq, addr, suspect_id, suspect_addr)),
+            other => {
+                warn!("request => {:?} is not sent over udp", other);
+                None
+            }
+        };
+
+        // unsupported requests drop the sender, failing the response
+        if let Some(datagram) = datagram {
+            self.pending.lock().unwrap().insert(seq, tx);
+            let _ = self.outgoing_tx.unbounded_send((datagram, peer_addr));
+        }
+
+        let pending = self.pending.clone();
+        rx.map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "[udp] response canceled")
+        }).timeout(timeout).then(move |result| {
+            pending.lock().unwrap().remove(&seq);
+            result
+        })
+    }
+
+    /// Binds the socket and passes every incoming request to the handler
+    /// along with a sender through which its ack is returned.
+    pub fn listen<F>(&self, handler: F) -> io::Result<impl Future<Item = (), Error = ()>>
+        where
+          F: Fn(Request, ResponseTx) + Send + 'static
+    {
+        let outgoing_rx = match self.outgoing_rx.lock().unwrap().take() {
+            Some(rx) => rx,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "[udp] already listening")),
+        };
+        // separate handles to the socket for reading and writing, so that a
+        // failed send only loses its own datagram
+        let socket = net::UdpSocket::bind(&self.addr)?;
+        let send_socket = Arc::new(Mutex::new(UdpSocket::from_std(socket.try_clone()?, &Handle::default())?));
+        let socket = UdpSocket::from_std(socket, &Handle::default())?;
+        let stream = UdpFramed::new(socket, BincodeCodec::<Datagram>::new(self.keyring.clone()));
+
+        let mut codec = BincodeCodec::<Datagram>::new(self.keyring.clone());
+        let writer = outgoing_rx.for_each(move |(datagram, peer_addr)| {
+            let mut frame = BytesMut::new();
+            if let Err(err) = codec.encode(datagram, &mut frame) {
+                warn!("send to {:?} => {:?}", peer_addr, err);
+                return future::Either::A(future::ok(()));
+            }
+            let socket = send_socket.clone();
+            let frame_len = frame.len();
+            let send = future::poll_fn(move || socket.lock().unwrap().poll_send_to(&frame, &peer_addr))
+                .then(move |result| -> Result<(), ()> {
+                    match result {
+                        Ok(len) if len < frame_len => {
+                            warn!("send to {:?} => sent {} of {} bytes", peer_addr, len, frame_len);
+                            Ok(())
+                        }
+                        Ok(_) => Ok(()),
+                        Err(err) => {
+                            warn!("send to {:?} => {:?}", peer_addr, err);
+                            Ok(())
+                        }
+                    }
+                });
+            future::Either::B(send)
+        });
+
+        let pending = self.pending.clone();
+        let outgoing_tx = self.outgoing_tx.clone();
+        let reader = stream
+            // a malformed datagram must not stop the listener
+            .then(|result| -> Result<Option<(Datagram, SocketAddr)>, ()> {
+                match result {
+                    Ok((Some(datagram), peer_addr)) => Ok(Some((datagram, peer_addr))),
+                    // a type unknown to this version
+                    Ok((None, _)) => Ok(None),
+                    Err(err) => {
+                        warn!("listen => {:?}", err);
+                        Ok(None)
+                    }
+                }
+            })
+            .filter_map(|datagram| datagram)
+            .for_each(move |(datagram, peer_addr)| {
+                debug!("RECV={:?}", datagram);
+                let (seq, request) = match datagram {
+                    Datagram::Ack(seq, gossip) => {
+                        if let Some(tx) = pending.lock().unwrap().remove(&seq) {
+                            let _ = tx.send(Response::Ack(gossip));
+                        }
+                        return Ok(());
+                    }
+                    Datagram::Nack(seq) => {
+                        if let Some(tx) = pending.lock().unwrap().remove(&seq) {
+                            let _ = tx.send(Response::Nack);
+                        }
+                        return Ok(());
+                    }
+                    Datagram::Ping(seq, addr, target_id, gossip) =>
+                        (seq, Request::Ping(addr, target_id, gossip)),
+                    Datagram::PingReq(seq, addr, suspect_id, suspect_addr) =>
+                        (seq, Request::PingReq(addr, suspect_id, suspect_addr)),
+                };
+
+                // Forward acks back to the requester under its sequence number
+                let (tx, rx) = mpsc::unbounded();
+                let outgoing_tx = outgoing_tx.clone();
+                let forward = rx.for_each(move |response| {
+                    let datagram = match response {
+                        Response::Ack(gossip) => Datagram::Ack(seq, gossip),
+                        Response::Nack => Datagram::Nack(seq),
+                        _ => return Ok(()),
+                    };
+                    let _ = outgoing_tx.unbounded_send((datagram, peer_addr));
+                    Ok(())
+                });
+                tokio::spawn(forward);
+
+                handler(request, tx);
+                Ok(())
+            });
+
+        info!("listening for datagrams at {:?}", self.addr);
+        Ok(reader.join(writer).map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use tokio::codec::Decoder;
+    use tokio::runtime::Runtime;
+    use types::{NetAddr, NodeId};
+
+    #[test]
+    fn test_match_acks_by_sequence() {
+        // the peer acks the first ping under the wrong sequence number
+        let peer = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut codec = BincodeCodec::<Datagram>::new(Keyring::default());
+            let mut buf = vec![0u8; 65536];
+            for skew in &[1, 0] {
+                let (len, addr) = peer.recv_from(&mut buf).unwrap();
+                if let Ok(Some(Some(Datagram::Ping(seq, ..)))) = codec.decode(&mut BytesMut::from(&buf[..len])) {
+                    let mut frame = BytesMut::new();
+                    codec.encode(Datagram::Ack(seq + skew, vec![]), &mut frame).unwrap();
+                    peer.send_to(&frame, addr).unwrap();
+                }
+            }
+        });
+
+        let transport = Transport::new("127.0.0.1:0".parse().unwrap(), Keyring::default());
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(transport.listen(|_, _| {}).unwrap());
+        let ping = || Request::Ping(NetAddr::new(transport.addr), NodeId::new("peer"), vec![]);
+        let timeout = Duration::from_millis(500);
+        assert!(runtime.block_on(transport.request(peer_addr, ping(), timeout)).is_err());
+        match runtime.block_on(transport.request(peer_addr, ping(), timeout)) {
+            Ok(Response::Ack(gossip)) => assert!(gossip.is_empty()),
+            _ => panic!("expected an ack"),
+        }
+    }
+}